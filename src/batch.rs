@@ -0,0 +1,423 @@
+use std::{
+    ffi::OsStr,
+    fmt,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{command::Command, error::RashError, output::Output};
+
+/// Run every one of `commands`, at most `max_parallel` at a time, carrying on whatever fails.
+/// Shorthand for `Batch::new(commands).max_parallel(max_parallel).run()`.
+///
+/// ```
+/// use rsbash::{run_all, Command};
+///
+/// let hosts = ["alpha", "beta", "gamma"];
+/// let output = run_all(hosts.iter().map(|host| Command::new(format!("echo {host}"))), 2);
+/// assert_eq!(output.summary.succeeded, 3);
+/// let stdouts: Vec<_> = output.outputs().map(|output| output.unwrap().stdout.clone()).collect();
+/// assert_eq!(stdouts, ["alpha\n", "beta\n", "gamma\n"]);
+/// ```
+pub fn run_all<I: IntoIterator<Item = Command>>(commands: I, max_parallel: usize) -> BatchOutput {
+    Batch::new(commands).max_parallel(max_parallel).run()
+}
+
+/// Run `script` once for each of `inputs`, at most `jobs` at a time, with the input's items
+/// as its positional parameters `$1`, `$2` and so on, like `xargs -P`. Shorthand for
+/// `Batch::map(&Command::new(script), inputs).max_parallel(jobs).run()`.
+///
+/// The inputs are passed as args, never formatted into the script, so they needn't be
+/// quoted. See [`Command::arg`].
+///
+/// ```
+/// use rsbash::map;
+///
+/// let files = ["a.txt", "it's here.txt", "$(reboot).txt"];
+/// let output = map("echo \"${1%.txt}\"", files.iter().map(|file| [file]), 2);
+/// let stdouts: Vec<_> = output.outputs().map(|output| output.unwrap().stdout.clone()).collect();
+/// assert_eq!(stdouts, ["a\n", "it's here\n", "$(reboot)\n"]);
+/// ```
+pub fn map<S, I>(script: S, inputs: I, jobs: usize) -> BatchOutput
+where
+    S: AsRef<str>,
+    I: IntoIterator,
+    I::Item: IntoIterator,
+    <I::Item as IntoIterator>::Item: AsRef<OsStr>,
+{
+    Batch::map(&Command::new(script), inputs).max_parallel(jobs).run()
+}
+
+/// Run every one of `commands` on the current rayon thread pool, carrying on whatever fails.
+/// Shorthand for `Batch::new(commands).par_run()`.
+#[cfg(feature = "rayon")]
+pub fn par_run_all<I: IntoIterator<Item = Command>>(commands: I) -> BatchOutput {
+    Batch::new(commands).par_run()
+}
+
+/// A set of [`Command`]s to be run side by side, e.g. the same script over lots of hosts or
+/// files.
+///
+/// The results come back in the order the commands were given, however they finish.
+#[derive(Clone)]
+pub struct Batch {
+    commands: Vec<Command>,
+    max_parallel: usize,
+    fail_fast: bool,
+    on_progress: Option<Arc<ProgressFn>>,
+}
+
+/// A callback given to [`Batch::on_progress`].
+type ProgressFn = dyn Fn(Progress) + Send + Sync;
+
+impl fmt::Debug for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Batch")
+            .field("commands", &self.commands)
+            .field("max_parallel", &self.max_parallel)
+            .field("fail_fast", &self.fail_fast)
+            .field("on_progress", &self.on_progress.is_some())
+            .finish()
+    }
+}
+
+/// How far a [`Batch`] has got, as passed to its [`on_progress`](Batch::on_progress)
+/// callback each time a command finishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Which command just finished, counting from 0 in the order they were given.
+    pub index: usize,
+    /// Whether it returned 0.
+    pub succeeded: bool,
+    /// How many commands have finished so far, including this one.
+    pub completed: usize,
+    /// How many commands there are.
+    pub total: usize,
+}
+
+/// The results of a [`Batch`], as returned by [`Batch::run`].
+#[derive(Debug)]
+pub struct BatchOutput {
+    /// What each command returned, in the order they were given. `None` for those which were
+    /// never started, because an earlier one failed with [`Batch::fail_fast`].
+    pub results: Vec<Option<Result<Output, RashError>>>,
+    /// How the batch went, overall.
+    pub summary: BatchSummary,
+}
+
+/// How a [`Batch`] went, overall.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    /// How many commands there were.
+    pub total: usize,
+    /// How many returned 0.
+    pub succeeded: usize,
+    /// How many returned anything else.
+    pub failed: usize,
+    /// How many returned an error.
+    pub errored: usize,
+    /// How many were never started.
+    pub skipped: usize,
+    /// How long the whole batch took.
+    pub elapsed: Duration,
+}
+
+impl Batch {
+    /// A batch of `commands`, to be run as many at a time as there are CPUs.
+    pub fn new<I: IntoIterator<Item = Command>>(commands: I) -> Self {
+        Self {
+            commands: commands.into_iter().collect(),
+            max_parallel: 0,
+            fail_fast: false,
+            on_progress: None,
+        }
+    }
+
+    /// A batch running `command` once for each of `inputs`, with the input's items added to
+    /// its [args](Command::args).
+    pub fn map<I>(command: &Command, inputs: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: IntoIterator,
+        <I::Item as IntoIterator>::Item: AsRef<OsStr>,
+    {
+        Self::new(inputs.into_iter().map(|args| command.clone().args(args)))
+    }
+
+    /// Run no more than `max_parallel` commands at a time. 0, the default, means as many as
+    /// there are CPUs.
+    pub fn max_parallel(mut self, max_parallel: usize) -> Self {
+        self.max_parallel = max_parallel;
+        self
+    }
+
+    /// Whether to stop starting commands once one has failed, whether by returning an error
+    /// or anything but 0, rather than running them all regardless. Those already running are
+    /// left to finish. Defaults to `false`.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Call `on_progress` each time a command finishes, e.g. to drive a progress bar. It's
+    /// called from whichever thread ran the command, so may be called for more than one at
+    /// once.
+    pub fn on_progress<F: Fn(Progress) + Send + Sync + 'static>(mut self, on_progress: F) -> Self {
+        self.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+
+    /// Run the batch, blocking until every command has finished or been skipped.
+    pub fn run(&self) -> BatchOutput {
+        let start = Instant::now();
+        let completed = AtomicUsize::new(0);
+        let results = run_jobs(self.commands.len(), self.max_parallel, self.fail_fast, |i| {
+            self.output(i, &completed)
+        });
+        BatchOutput::new(results, start)
+    }
+
+    /// Run the batch on the current rayon thread pool, rather than threads of its own, so
+    /// that it shares the pool's limit on how much runs at once with everything else using
+    /// it. [`Batch::max_parallel`] doesn't apply.
+    #[cfg(feature = "rayon")]
+    pub fn par_run(&self) -> BatchOutput {
+        use rayon::prelude::*;
+
+        let start = Instant::now();
+        let failed = AtomicBool::new(false);
+        let completed = AtomicUsize::new(0);
+        let results = (0..self.commands.len())
+            .into_par_iter()
+            .map(|i| {
+                if self.fail_fast && failed.load(Ordering::Relaxed) {
+                    return None;
+                }
+                let result = self.output(i, &completed);
+                if !succeeded(&result) {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                Some(result)
+            })
+            .collect();
+        BatchOutput::new(results, start)
+    }
+
+    /// Run the `i`th command, reporting its progress, with `completed` counting those which
+    /// have finished.
+    fn output(&self, i: usize, completed: &AtomicUsize) -> Result<Output, RashError> {
+        let result = self.commands[i].output();
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(Progress {
+                index: i,
+                succeeded: succeeded(&result),
+                completed: completed.fetch_add(1, Ordering::Relaxed) + 1,
+                total: self.commands.len(),
+            });
+        }
+        result
+    }
+}
+
+impl BatchOutput {
+    fn new(results: Vec<Option<Result<Output, RashError>>>, start: Instant) -> Self {
+        let mut summary = BatchSummary {
+            total: results.len(),
+            elapsed: start.elapsed(),
+            ..BatchSummary::default()
+        };
+        for result in &results {
+            match result {
+                Some(Ok(output)) if output.ret_val == 0 => summary.succeeded += 1,
+                Some(Ok(_)) => summary.failed += 1,
+                Some(Err(_)) => summary.errored += 1,
+                None => summary.skipped += 1,
+            }
+        }
+        Self {
+            results,
+            summary,
+        }
+    }
+
+    /// Each command's output, in order: `None` if it was skipped or returned an error.
+    pub fn outputs(&self) -> impl Iterator<Item = Option<&Output>> {
+        self.results.iter().map(|result| result.as_ref().and_then(|result| result.as_ref().ok()))
+    }
+
+    /// Whether every command returned 0.
+    pub fn succeeded(&self) -> bool {
+        self.summary.succeeded == self.summary.total
+    }
+}
+
+/// Whether a command's `result` counts as a success: it ran, and returned 0.
+fn succeeded(result: &Result<Output, RashError>) -> bool {
+    result.as_ref().is_ok_and(|output| output.ret_val == 0)
+}
+
+/// Call `job` with each index up to `count`, on up to `max_parallel` threads at once (or as
+/// many as there are CPUs, if 0), returning the results in order. With `fail_fast`, indices
+/// not yet started once a job has failed are skipped, and their results are `None`.
+pub(crate) fn run_jobs<F>(
+    count: usize,
+    max_parallel: usize,
+    fail_fast: bool,
+    job: F,
+) -> Vec<Option<Result<Output, RashError>>>
+where
+    F: Fn(usize) -> Result<Output, RashError> + Sync,
+{
+    let threads = match max_parallel {
+        0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        n => n,
+    };
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(count) {
+            scope.spawn(|| loop {
+                if fail_fast && failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= count {
+                    break;
+                }
+                let result = job(i);
+                if !succeeded(&result) {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{map, run_all, Batch};
+    use crate::{Command, RashError};
+
+    fn sleeps(n: usize) -> impl Iterator<Item = Command> {
+        (0..n).map(|i| Command::new(format!("sleep 0.2; echo {i}")))
+    }
+
+    #[test]
+    fn test_run_all_keeps_order() {
+        let commands = (0..8).map(|i| Command::new(format!("sleep 0.0{}; echo {i}", 8 - i)));
+        let output = run_all(commands, 4);
+        let stdouts: Vec<_> =
+            output.outputs().map(|output| output.unwrap().stdout.clone()).collect();
+        assert_eq!(stdouts, (0..8).map(|i| format!("{i}\n")).collect::<Vec<_>>());
+        assert!(output.succeeded());
+    }
+
+    #[test]
+    fn test_run_all_is_bounded() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("running"))?;
+        // Each command counts those running alongside it, itself included.
+        let script = "touch running/$1; sleep 0.2; ls running | wc -l >> counts; rm running/$1";
+        let commands = (0..6).map(|i| Command::new(script).arg(i.to_string()).current_dir(&dir));
+        let output = run_all(commands, 3);
+        assert_eq!(output.summary.succeeded, 6);
+        let counts = std::fs::read_to_string(dir.path().join("counts"))?;
+        let counts =
+            counts.lines().map(|count| count.trim().parse()).collect::<Result<Vec<usize>, _>>()?;
+        let most = counts.iter().max().copied().unwrap_or(0);
+        Ok(assert!((2..=3).contains(&most), "{counts:?}"))
+    }
+
+    #[test]
+    fn test_run_all_collects_everything() {
+        let commands =
+            ["true", "exit 3", "i_am_not_a_valid_executable", "echo hi"].map(Command::new);
+        let output = run_all(commands, 2);
+        let summary = output.summary;
+        assert_eq!((summary.total, summary.succeeded, summary.failed), (4, 2, 1));
+        assert_eq!((summary.errored, summary.skipped), (1, 0));
+        assert!(matches!(output.results[2], Some(Err(RashError::CommandNotFound { .. }))));
+        assert_eq!(
+            output.outputs().map(|output| output.map(|o| o.ret_val)).collect::<Vec<_>>(),
+            [Some(0), Some(3), None, Some(0)]
+        );
+    }
+
+    #[test]
+    fn test_batch_fail_fast() {
+        let commands = std::iter::once(Command::new("exit 1")).chain(sleeps(10));
+        let output = Batch::new(commands).max_parallel(1).fail_fast(true).run();
+        let summary = output.summary;
+        assert_eq!((summary.failed, summary.skipped), (1, 10));
+        assert!(output.results[1..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_batch_defaults_to_one_per_cpu() {
+        assert_eq!(Batch::new(sleeps(2)).run().summary.succeeded, 2);
+    }
+
+    #[test]
+    fn test_map_passes_inputs_as_args() {
+        let inputs = [vec!["a", "b c"], vec![], vec!["'; exit 1; '"]];
+        let output = map("echo \"$#:$*\"", inputs, 2);
+        let stdouts: Vec<_> =
+            output.outputs().map(|output| output.unwrap().stdout.clone()).collect();
+        assert_eq!(stdouts, ["2:a b c\n", "0:\n", "1:'; exit 1; '\n"]);
+    }
+
+    #[test]
+    fn test_map_stops_on_first_failure() {
+        // The failure comes well before the command running alongside it finishes.
+        let command = Command::new("[ \"$1\" != 2 ] && sleep 0.1");
+        let output = Batch::map(&command, (0..10).map(|i| [i.to_string()]))
+            .max_parallel(2)
+            .fail_fast(true)
+            .run();
+        let summary = output.summary;
+        assert_eq!((summary.succeeded, summary.failed, summary.skipped), (3, 1, 6));
+        assert!(output.results[4..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_batch_on_progress() {
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let seen = progress.clone();
+        let output = Batch::new(["true", "false", "true"].map(Command::new))
+            .max_parallel(1)
+            .on_progress(move |progress| seen.lock().unwrap().push(progress))
+            .run();
+        assert_eq!(output.summary.total, 3);
+        let progress = progress.lock().unwrap();
+        let summary: Vec<_> =
+            progress.iter().map(|p| (p.index, p.succeeded, p.completed)).collect();
+        assert_eq!(summary, [(0, true, 1), (1, false, 2), (2, true, 3)]);
+        assert!(progress.iter().all(|p| p.total == 3));
+    }
+
+    #[test]
+    fn test_batch_of_nothing() {
+        let output = run_all(std::iter::empty(), 4);
+        assert_eq!((output.summary.total, output.results.len()), (0, 0));
+        assert!(output.succeeded());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_batch_par_run() {
+        let commands = std::iter::once(Command::new("exit 1")).chain(sleeps(3));
+        let output = super::par_run_all(commands);
+        let summary = output.summary;
+        assert_eq!((summary.total, summary.succeeded, summary.failed), (4, 3, 1));
+        assert_eq!(output.outputs().last().unwrap().unwrap().stdout, "2\n");
+    }
+}
@@ -0,0 +1,932 @@
+use libc::{
+    __errno_location, _exit, c_char, c_int, c_uint, c_void, chdir, chroot, close, cpu_set_t, dup2,
+    execve, fcntl, fork, getpid, getppid, gid_t, kill, mode_t, open, pollfd, ppoll, prctl,
+    pthread_sigmask, read, rlim_t, rlimit, sched_setaffinity, setgid, setgroups, setpgid,
+    setpriority, setrlimit, setuid, sigaction, sigaddset, sigdelset, sigemptyset, sigfillset,
+    siginfo_t, sigset_t, sigsuspend, socketpair, syscall, sysconf, uid_t, umask, waitpid, write,
+    SYS_close_range, _SC_OPEN_MAX, AF_UNIX, CPU_SET, CPU_SETSIZE, EINTR, F_DUPFD, F_DUPFD_CLOEXEC,
+    F_SETFD, O_CLOEXEC, O_RDONLY, POLLIN, PRIO_PROCESS, PR_SET_CHILD_SUBREAPER, PR_SET_PDEATHSIG,
+    RLIMIT_AS, RLIMIT_CORE, RLIMIT_CPU, RLIMIT_FSIZE, RLIMIT_NOFILE, RLIMIT_NPROC, RLIM_INFINITY,
+    SA_SIGINFO, SIGCHLD, SIGHUP, SIGINT, SIGKILL, SIGPIPE, SIGQUIT, SIGSTOP, SIGTERM, SIGUSR1,
+    SIGUSR2, SIG_DFL, SIG_IGN, SIG_SETMASK, SIG_UNBLOCK, SOCK_CLOEXEC, SOCK_STREAM, WEXITSTATUS,
+    WIFSIGNALED, WNOHANG, WTERMSIG,
+};
+use std::{
+    ffi::{CString, NulError, OsString},
+    fmt,
+    mem::{size_of, MaybeUninit},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+};
+
+use crate::{
+    command::BashCommand,
+    error::SpawnStage,
+    forward::FORWARDED_SIGNALS as RELAYED_SIGNALS,
+    landlock::{Landlock, LandlockPlan},
+    sandbox::{Sandbox, SandboxPlan},
+    seccomp::{Seccomp, SeccompPlan},
+};
+
+/// What the child writes down the report pipe if it fails before exec: the stage, then errno.
+pub(crate) type SpawnReport = [c_int; 2];
+
+/// What the supervisor writes down the stragglers pipe for each descendant it had to kill,
+/// and each system call its [`Seccomp`] filter blocked, after first writing the pid of the
+/// script itself.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SupervisorRecord {
+    /// [`SupervisorRecord::STRAGGLER`] or [`SupervisorRecord::BLOCKED_SYSCALL`].
+    pub(crate) kind: c_int,
+    pub(crate) pid: c_int,
+    /// The number of the blocked system call, or 0 for a straggler.
+    pub(crate) syscall: c_int,
+    /// `/proc/<pid>/comm`, null padded.
+    pub(crate) comm: [u8; 16],
+}
+
+impl SupervisorRecord {
+    pub(crate) const STRAGGLER: c_int = 0;
+    pub(crate) const BLOCKED_SYSCALL: c_int = 1;
+
+    /// Write the record down `fd`, without allocating.
+    unsafe fn write(&self, fd: c_int) {
+        let record = self as *const Self as *const c_void;
+        write(fd, record, size_of::<Self>());
+    }
+}
+
+/// The signals a supervisor passes on to the script, rather than dying of them itself.
+const FORWARDED_SIGNALS: [c_int; 6] = [SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2];
+
+/// The pid of the script, in a supervisor. Each forked supervisor has its own copy.
+static SUPERVISED: AtomicI32 = AtomicI32::new(-1);
+
+/// The `si_code` of a signal sent with kill(2), which libc doesn't define for Linux.
+const SI_USER: c_int = 0;
+
+/// Whether the host relays its signals to the script's process group, in a supervisor.
+static RELAYED: AtomicBool = AtomicBool::new(false);
+
+/// Does nothing, but interrupts whatever the supervisor was blocked in.
+extern "C" fn wake(_: c_int) {}
+
+extern "C" fn forward(signal: c_int, info: *mut siginfo_t, _: *mut c_void) {
+    unsafe {
+        // The host's relay signals the whole process group, script and all, so passing that
+        // on would have the script see it twice.
+        if RELAYED.load(Ordering::Relaxed)
+            && RELAYED_SIGNALS.contains(&signal)
+            && (*info).si_code == SI_USER
+            && (*info).si_pid() == getppid()
+        {
+            return;
+        }
+        let errno = *__errno_location();
+        kill(SUPERVISED.load(Ordering::Relaxed), signal);
+        *__errno_location() = errno;
+    }
+}
+
+/// A resource limit which can be put on a [`Command`](crate::Command) with
+/// [`Command::limit`](crate::Command::limit). See setrlimit(2) for the details of each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// `RLIMIT_CPU`: seconds of CPU time. The command is sent `SIGXCPU` once it's used this
+    /// much, and `SIGKILL` a second later.
+    Cpu,
+    /// `RLIMIT_AS`: bytes of virtual memory, per process.
+    AddressSpace,
+    /// `RLIMIT_NOFILE`: one more than the highest fd each process can open.
+    OpenFiles,
+    /// `RLIMIT_NPROC`: processes (strictly, threads) for the user the command runs as, counting
+    /// those it already has.
+    Processes,
+    /// `RLIMIT_FSIZE`: bytes any one file can be grown to. Going over sends `SIGXFSZ`.
+    FileSize,
+    /// `RLIMIT_CORE`: bytes of core dump. 0 disables them.
+    Core,
+}
+
+impl Limit {
+    fn resource(&self) -> libc::__rlimit_resource_t {
+        match self {
+            Self::Cpu => RLIMIT_CPU,
+            Self::AddressSpace => RLIMIT_AS,
+            Self::OpenFiles => RLIMIT_NOFILE,
+            Self::Processes => RLIMIT_NPROC,
+            Self::FileSize => RLIMIT_FSIZE,
+            Self::Core => RLIMIT_CORE,
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cpu => "RLIMIT_CPU",
+            Self::AddressSpace => "RLIMIT_AS",
+            Self::OpenFiles => "RLIMIT_NOFILE",
+            Self::Processes => "RLIMIT_NPROC",
+            Self::FileSize => "RLIMIT_FSIZE",
+            Self::Core => "RLIMIT_CORE",
+        })
+    }
+}
+
+/// How the child should set up its signal dispositions and mask before exec.
+#[derive(Debug, Clone)]
+pub(crate) struct Signals {
+    /// Restore every disposition to its default and clear the mask.
+    /// When false, the child keeps whatever it inherited from the spawning thread.
+    pub(crate) reset: bool,
+    pub(crate) ignore: Vec<c_int>,
+    pub(crate) block: Vec<c_int>,
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self {
+            reset: true,
+            ignore: Vec::new(),
+            block: Vec::new(),
+        }
+    }
+}
+
+/// The options which shape the child before it execs, as set on a [`Command`](crate::Command).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChildOptions {
+    pub(crate) signals: Signals,
+    pub(crate) current_dir: Option<PathBuf>,
+    /// Variables to set, or with `None` to remove, in order, on top of our own environment,
+    /// or of an empty one if `env_clear`.
+    pub(crate) env: Vec<(OsString, Option<OsString>)>,
+    pub(crate) env_clear: bool,
+    pub(crate) reap_orphans: bool,
+    pub(crate) forward_signals: bool,
+    pub(crate) limits: Vec<(Limit, u64)>,
+    pub(crate) sandbox: Option<Sandbox>,
+    pub(crate) landlock: Option<Landlock>,
+    pub(crate) seccomp: Option<Seccomp>,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) groups: Option<Vec<u32>>,
+    pub(crate) umask: Option<u32>,
+    pub(crate) chroot: Option<PathBuf>,
+    pub(crate) nice: Option<i32>,
+    pub(crate) cpu_affinity: Option<Vec<usize>>,
+}
+
+/// Everything the forked child needs in order to exec the command.
+///
+/// Between `fork()` and `execve()` a child of a multithreaded parent may only make
+/// async-signal-safe calls, since another thread could have been holding a lock
+/// (e.g. the allocator's) at the moment we forked. So the argv, envp and fd plan
+/// are all built here, in the parent, and [`ChildPlan::exec`] only touches raw syscalls.
+pub(crate) struct ChildPlan {
+    path: CString,
+    // The pointer arrays below point into these, so they must outlive the exec.
+    _argv: Vec<CString>,
+    _envp: Vec<CString>,
+    argv_ptrs: Vec<*const c_char>,
+    envp_ptrs: Vec<*const c_char>,
+    max_fd: c_int,
+    max_signal: c_int,
+    signals: Signals,
+    current_dir: Option<CString>,
+    reap_orphans: bool,
+    children: CString,
+    process_group: bool,
+    limits: Vec<(libc::__rlimit_resource_t, rlimit)>,
+    sandbox: Option<SandboxPlan>,
+    landlock: Option<LandlockPlan>,
+    seccomp: Option<SeccompPlan>,
+    uid: Option<uid_t>,
+    gid: Option<gid_t>,
+    groups: Option<Vec<gid_t>>,
+    umask: Option<mode_t>,
+    chroot: Option<CString>,
+    nice: Option<c_int>,
+    cpu_affinity: Option<cpu_set_t>,
+    /// Up to two fds above 2 which the script inherits at the same numbers, e.g. for it to
+    /// report back through besides its stdio, or -1.
+    inherit: [c_int; 2],
+}
+
+impl ChildPlan {
+    pub(crate) fn new(command: &BashCommand, options: &ChildOptions) -> Result<Self, NulError> {
+        let argv = command.argv();
+        let mut vars = match options.env_clear {
+            true => Vec::new(),
+            false => std::env::vars_os().collect::<Vec<_>>(),
+        };
+        for (key, value) in &options.env {
+            vars.retain(|(k, _)| k != key);
+            if let Some(value) = value {
+                vars.push((key.clone(), value.clone()));
+            }
+        }
+        let envp = vars
+            .into_iter()
+            .map(|(k, v)| {
+                let mut pair = Vec::with_capacity(k.len() + v.len() + 1);
+                pair.extend_from_slice(k.as_bytes());
+                pair.push(b'=');
+                pair.extend_from_slice(v.as_bytes());
+                // The OS guarantees its own environment is free of null bytes, but ours may not be.
+                CString::new(pair)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let argv_ptrs = Self::null_terminated(&argv);
+        let envp_ptrs = Self::null_terminated(&envp);
+        let max_fd = match unsafe { sysconf(_SC_OPEN_MAX) } {
+            n if n > 0 => n.min(c_int::MAX as _) as c_int,
+            _ => 1024,
+        };
+        let current_dir = match &options.current_dir {
+            Some(dir) => Some(CString::new(dir.as_os_str().as_bytes())?),
+            None => None,
+        };
+        let chroot = match &options.chroot {
+            Some(dir) => Some(CString::new(dir.as_os_str().as_bytes())?),
+            None => None,
+        };
+        // Changing user or group shouldn't leave the new one in the old one's groups.
+        let groups = match (&options.groups, options.uid.or(options.gid)) {
+            (Some(groups), _) => Some(groups.clone()),
+            (None, Some(_)) => Some(Vec::new()),
+            (None, None) => None,
+        };
+        let cpu_affinity = options.cpu_affinity.as_ref().map(|cpus| {
+            let mut set = unsafe { MaybeUninit::<cpu_set_t>::zeroed().assume_init() };
+            // CPUs beyond what a cpu_set_t can hold can't exist, just as others may not.
+            for cpu in cpus.iter().filter(|cpu| **cpu < CPU_SETSIZE as usize) {
+                unsafe { CPU_SET(*cpu, &mut set) };
+            }
+            set
+        });
+        Ok(Self {
+            path: argv[0].clone(),
+            _argv: argv,
+            _envp: envp,
+            argv_ptrs,
+            envp_ptrs,
+            max_fd,
+            max_signal: libc::SIGRTMAX(),
+            signals: options.signals.clone(),
+            current_dir,
+            reap_orphans: options.reap_orphans,
+            children: CString::new("/proc/thread-self/children")?,
+            process_group: options.forward_signals,
+            limits: options
+                .limits
+                .iter()
+                .map(|(limit, value)| {
+                    let soft = *value as rlim_t;
+                    // The kernel skips straight to SIGKILL at the hard CPU limit, so leave a
+                    // second after the soft one for SIGXCPU.
+                    let hard = match limit {
+                        Limit::Cpu if soft != RLIM_INFINITY => soft.saturating_add(1),
+                        _ => soft,
+                    };
+                    let rlimit = rlimit {
+                        rlim_cur: soft,
+                        rlim_max: hard,
+                    };
+                    (limit.resource(), rlimit)
+                })
+                .collect(),
+            sandbox: options.sandbox.as_ref().map(SandboxPlan::new).transpose()?,
+            landlock: options.landlock.as_ref().map(LandlockPlan::new),
+            seccomp: options.seccomp.as_ref().map(SeccompPlan::new),
+            uid: options.uid,
+            gid: options.gid,
+            groups,
+            umask: options.umask,
+            chroot,
+            nice: options.nice,
+            cpu_affinity,
+            inherit: [-1, -1],
+        })
+    }
+
+    /// Whether the child is a supervisor, which needs a stragglers pipe passing to [`fork`].
+    /// Sandboxed scripts are run under one too, as they have to be forked into their new pid
+    /// namespace, as are those with a seccomp filter, whose violations the supervisor handles.
+    ///
+    /// [`fork`]: ChildPlan::fork
+    pub(crate) fn supervised(&self) -> bool {
+        self.reap_orphans || self.sandbox.is_some() || self.seccomp.is_some()
+    }
+
+    /// The Landlock ABI version the child will be restricted with, if it was asked to be:
+    /// 0 means Landlock isn't supported, and the command will run unrestricted.
+    pub(crate) fn landlock_abi(&self) -> Option<u32> {
+        self.landlock.as_ref().map(LandlockPlan::abi)
+    }
+
+    /// Have the script inherit `fd`, which must be above 2, at the same number, rather than it
+    /// being closed with everything else.
+    pub(crate) fn inherit(&mut self, fd: c_int) {
+        let slot = self.inherit.iter_mut().find(|slot| **slot == -1);
+        *slot.expect("a child can only inherit two fds") = fd;
+    }
+
+    /// Whether the child leads a new process group, for the host's signals to be relayed to.
+    pub(crate) fn forwards_signals(&self) -> bool {
+        self.process_group
+    }
+
+    /// Fork, and exec the command in the child. Returns the child's pid, or -1 if we
+    /// couldn't fork.
+    ///
+    /// If the child fails before it manages to exec, it writes a [`SpawnReport`] to `report`,
+    /// which should be the write end of an `O_CLOEXEC` pipe: a successful exec closes it
+    /// without anything having been written.
+    ///
+    /// If [`supervised`](ChildPlan::supervised), `stragglers` should be the write end of
+    /// another `O_CLOEXEC` pipe, and the child becomes a supervisor (see
+    /// [`ChildPlan::supervise`]). Otherwise it should be -1.
+    ///
+    /// All signals are blocked across the fork, so none of the host's handlers can run in
+    /// the child before it has had the chance to reset them.
+    pub(crate) unsafe fn fork(
+        &self,
+        stdio: [c_int; 3],
+        parent_ends: [c_int; 3],
+        report: c_int,
+        stragglers: c_int,
+    ) -> c_int {
+        let mut all = MaybeUninit::<sigset_t>::uninit();
+        let mut inherited = MaybeUninit::<sigset_t>::uninit();
+        sigfillset(all.as_mut_ptr());
+        pthread_sigmask(SIG_SETMASK, all.as_ptr(), inherited.as_mut_ptr());
+        match fork() {
+            0 => self.exec(stdio, parent_ends, report, stragglers, inherited.assume_init_ref()),
+            pid => {
+                pthread_sigmask(SIG_SETMASK, inherited.as_ptr(), std::ptr::null_mut());
+                pid
+            }
+        }
+    }
+
+    /// Wire `stdio` up as the child's fds 0, 1 and 2, close the parent's ends of the pipes,
+    /// set up signals and exec. Only ever call this in the child, straight after `fork()`.
+    ///
+    /// Everything in here must stay async-signal-safe: no allocation, no locks, no panics.
+    unsafe fn exec(
+        &self,
+        stdio: [c_int; 3],
+        parent_ends: [c_int; 3],
+        report: c_int,
+        stragglers: c_int,
+        inherited: &sigset_t,
+    ) -> ! {
+        for fd in parent_ends {
+            close(fd);
+        }
+
+        // If the parent had closed any of its own stdio, a pipe end may have landed on 0, 1 or 2,
+        // where the dup2 of an earlier stream would clobber it. Move those out of the way first.
+        let mut report = report;
+        if report < 3 {
+            report = fcntl(report, F_DUPFD_CLOEXEC, 3);
+            if report == -1 {
+                _exit(127);
+            }
+        }
+        let mut keep = [stragglers, self.landlock.as_ref().map_or(-1, LandlockPlan::fd)];
+        for fd in keep.iter_mut() {
+            if *fd != -1 && *fd < 3 {
+                *fd = fcntl(*fd, F_DUPFD_CLOEXEC, 3);
+                if *fd == -1 {
+                    Self::fail(report, SpawnStage::Dup);
+                }
+            }
+        }
+        let [stragglers, ruleset] = keep;
+        let mut stdio = stdio;
+        for fd in stdio.iter_mut() {
+            if *fd < 3 {
+                match fcntl(*fd, F_DUPFD, 3) {
+                    -1 => Self::fail(report, SpawnStage::Dup),
+                    moved => *fd = moved,
+                }
+            }
+        }
+
+        for (target, fd) in stdio.iter().enumerate() {
+            if dup2(*fd, target as c_int) == -1 {
+                Self::fail(report, SpawnStage::Dup);
+            }
+        }
+        // Stdout and stderr share an fd when stderr is sent to stdout.
+        for (i, fd) in stdio.iter().enumerate() {
+            if !stdio[..i].contains(fd) {
+                close(*fd);
+            }
+        }
+
+        let [first, second] = self.inherit;
+        self.close_inherited_fds([report, stragglers, ruleset, first, second]);
+        for fd in self.inherit {
+            if fd != -1 && fcntl(fd, F_SETFD, 0) == -1 {
+                Self::fail(report, SpawnStage::Dup);
+            }
+        }
+        self.setup_signals(inherited);
+
+        for (resource, limit) in &self.limits {
+            if setrlimit(*resource, limit) == -1 {
+                Self::fail(report, SpawnStage::Setrlimit);
+            }
+        }
+
+        // The parent does this too, so that it doesn't matter which of us gets there first.
+        if self.process_group && setpgid(0, 0) == -1 {
+            Self::fail(report, SpawnStage::Setpgid);
+        }
+
+        if let Some(sandbox) = &self.sandbox {
+            if let Err(stage) = sandbox.enter() {
+                Self::fail(report, stage);
+            }
+        }
+
+        let channel = match self.supervised() {
+            true => self.supervise(report, stragglers),
+            false => -1,
+        };
+
+        if let Some(sandbox) = &self.sandbox {
+            if let Err(stage) = sandbox.mount_proc() {
+                Self::fail(report, stage);
+            }
+        }
+
+        if let Err(stage) = self.change_identity() {
+            Self::fail(report, stage);
+        }
+
+        // Last, so that none of the above is restricted, including a supervisor's use of /proc.
+        if let Some(landlock) = &self.landlock {
+            if let Err(stage) = landlock.restrict(ruleset) {
+                Self::fail(report, stage);
+            }
+        }
+
+        // Very last, as the filter may deny any of the calls made above.
+        if let Some(seccomp) = &self.seccomp {
+            if let Err(stage) = seccomp.install(channel) {
+                Self::fail(report, stage);
+            }
+        }
+
+        execve(self.path.as_ptr(), self.argv_ptrs.as_ptr(), self.envp_ptrs.as_ptr());
+        Self::fail(report, SpawnStage::Exec);
+    }
+
+    /// Set the nice value and CPU affinity, change root, user and group, then directory and
+    /// umask. Only ever call this in the child, straight after `fork()`.
+    ///
+    /// On failure, returns the stage which failed, with errno left as it set it.
+    unsafe fn change_identity(&self) -> Result<(), SpawnStage> {
+        // Before giving up root, which lowering the nice value needs.
+        if let Some(nice) = self.nice {
+            if setpriority(PRIO_PROCESS, 0, nice) == -1 {
+                return Err(SpawnStage::Nice);
+            }
+        }
+        if let Some(cpus) = &self.cpu_affinity {
+            if sched_setaffinity(0, size_of::<cpu_set_t>(), cpus) == -1 {
+                return Err(SpawnStage::Affinity);
+            }
+        }
+        if let Some(root) = &self.chroot {
+            if chroot(root.as_ptr()) == -1 {
+                return Err(SpawnStage::Chroot);
+            }
+            // Otherwise we'd be left in a directory outside the new root.
+            if self.current_dir.is_none() && chdir(c"/".as_ptr()) == -1 {
+                return Err(SpawnStage::Chdir);
+            }
+        }
+
+        // Groups first, then the group, then the user, as each step gives up the privilege
+        // needed for the ones before it.
+        if let Some(groups) = &self.groups {
+            if setgroups(groups.len(), groups.as_ptr()) == -1 {
+                return Err(SpawnStage::Setgroups);
+            }
+        }
+        if let Some(gid) = self.gid {
+            if setgid(gid) == -1 {
+                return Err(SpawnStage::Setgid);
+            }
+        }
+        if let Some(uid) = self.uid {
+            if setuid(uid) == -1 {
+                return Err(SpawnStage::Setuid);
+            }
+        }
+        // Changing credentials clears the parent death signal, which a supervised script
+        // relies on to not outlive its supervisor.
+        if self.supervised()
+            && (self.uid.is_some() || self.gid.is_some())
+            && prctl(PR_SET_PDEATHSIG, SIGKILL) == -1
+        {
+            return Err(SpawnStage::Subreaper);
+        }
+
+        // After entering the sandbox, so that a directory made read-only within it is, and
+        // after changing user, so that it's only entered if they're allowed in.
+        if let Some(dir) = &self.current_dir {
+            if chdir(dir.as_ptr()) == -1 {
+                return Err(SpawnStage::Chdir);
+            }
+        }
+        if let Some(mask) = self.umask {
+            umask(mask);
+        }
+        Ok(())
+    }
+
+    /// Tell the parent which stage failed, and with what errno, then bail.
+    unsafe fn fail(report: c_int, stage: SpawnStage) -> ! {
+        let record: SpawnReport = [stage as c_int, *__errno_location()];
+        write(report, record.as_ptr() as *const c_void, size_of::<SpawnReport>());
+        _exit(127);
+    }
+
+    /// Become a subreaper, fork the script, and wait for it. Returns in the script's process,
+    /// which carries on to exec; the supervisor itself never returns.
+    ///
+    /// With a seccomp filter, the script returns its end of a socket to send the filter's
+    /// listener down, and the supervisor handles the notifications for denied calls while it
+    /// waits, reporting each one down `stragglers`. Otherwise the script returns -1.
+    ///
+    /// Once the script has exited, anything it left running has been reparented to us, so we
+    /// kill and reap it, reporting each one down `stragglers`. Then we exit the same way the
+    /// script did, so the parent can't tell us apart from it.
+    unsafe fn supervise(&self, report: c_int, stragglers: c_int) -> c_int {
+        if prctl(PR_SET_CHILD_SUBREAPER, 1) == -1 {
+            Self::fail(report, SpawnStage::Subreaper);
+        }
+        let mut channel = [-1, -1];
+        if self.seccomp.is_some()
+            && socketpair(AF_UNIX, SOCK_STREAM | SOCK_CLOEXEC, 0, channel.as_mut_ptr()) == -1
+        {
+            Self::fail(report, SpawnStage::Seccomp);
+        }
+        let supervisor = getpid();
+        let script = match fork() {
+            -1 => Self::fail(report, SpawnStage::Fork),
+            0 => {
+                close(stragglers);
+                if channel[0] != -1 {
+                    close(channel[0]);
+                }
+                // Don't outlive the supervisor, e.g. if the parent SIGKILLs it.
+                if prctl(PR_SET_PDEATHSIG, SIGKILL) == -1 {
+                    Self::fail(report, SpawnStage::Subreaper);
+                }
+                // In a new pid namespace our parent is outside it, so getppid() is always 0,
+                // and we can't tell whether the supervisor died before the prctl.
+                let parent = getppid();
+                if parent != supervisor && parent != 0 {
+                    _exit(127);
+                }
+                return channel[1];
+            }
+            pid => pid,
+        };
+        for fd in [0, 1, 2, report, channel[1]] {
+            if fd != -1 {
+                close(fd);
+            }
+        }
+
+        let mut action = MaybeUninit::<sigaction>::zeroed().assume_init();
+        action.sa_sigaction = SIG_IGN;
+        sigaction(SIGPIPE, &action, std::ptr::null_mut());
+        SUPERVISED.store(script, Ordering::Relaxed);
+        RELAYED.store(self.process_group, Ordering::Relaxed);
+        action.sa_sigaction = forward as extern "C" fn(c_int, *mut siginfo_t, *mut c_void) as usize;
+        action.sa_flags = SA_SIGINFO;
+        for signal in FORWARDED_SIGNALS {
+            sigaction(signal, &action, std::ptr::null_mut());
+        }
+        write(stragglers, &script as *const c_int as *const c_void, size_of::<c_int>());
+
+        let listener = match channel[0] {
+            -1 => -1,
+            channel => {
+                let listener = SeccompPlan::receive_listener(channel);
+                close(channel);
+                listener
+            }
+        };
+        let status = Self::wait_for(script, listener, stragglers);
+        self.reap_stragglers(stragglers);
+        close(stragglers);
+        Self::exit_like(status);
+    }
+
+    /// Wait for the script to exit, returning its status. Until it does, deny each call
+    /// notified on `listener` (-1 if there's no seccomp filter), reporting it down `stragglers`.
+    unsafe fn wait_for(script: c_int, listener: c_int, stragglers: c_int) -> c_int {
+        let mut status = 0;
+        if listener == -1 {
+            while waitpid(script, &mut status, 0) == -1 && *__errno_location() == EINTR {}
+            return status;
+        }
+
+        // SIGCHLD is only let through while we're polling, so that it's sure to interrupt the
+        // poll if the script exits, rather than arriving just before we start it.
+        let mut action = MaybeUninit::<sigaction>::zeroed().assume_init();
+        action.sa_sigaction = wake as extern "C" fn(c_int) as usize;
+        sigaction(SIGCHLD, &action, std::ptr::null_mut());
+        let mut mask = MaybeUninit::<sigset_t>::uninit();
+        sigemptyset(mask.as_mut_ptr());
+        sigaddset(mask.as_mut_ptr(), SIGCHLD);
+        let mut polling = MaybeUninit::<sigset_t>::uninit();
+        pthread_sigmask(libc::SIG_BLOCK, mask.as_ptr(), polling.as_mut_ptr());
+        sigdelset(polling.as_mut_ptr(), SIGCHLD);
+
+        let mut listening = true;
+        loop {
+            match waitpid(script, &mut status, WNOHANG) {
+                0 => {}
+                -1 if *__errno_location() == EINTR => continue,
+                _ => break,
+            }
+            if !listening {
+                sigsuspend(polling.as_ptr());
+                continue;
+            }
+            let mut fd = pollfd {
+                fd: listener,
+                events: POLLIN,
+                revents: 0,
+            };
+            if ppoll(&mut fd, 1, std::ptr::null(), polling.as_ptr()) < 1 {
+                continue;
+            }
+            if fd.revents & POLLIN != 0 {
+                SeccompPlan::deny(listener, |pid, syscall| {
+                    let record = SupervisorRecord {
+                        kind: SupervisorRecord::BLOCKED_SYSCALL,
+                        pid,
+                        syscall,
+                        comm: Self::comm(pid),
+                    };
+                    record.write(stragglers);
+                });
+            } else {
+                // Nothing's left which could make a filtered call.
+                listening = false;
+            }
+        }
+        close(listener);
+        status
+    }
+
+    /// Kill and reap every child we have, until there are none left: killing one can orphan
+    /// its own children onto us.
+    unsafe fn reap_stragglers(&self, stragglers: c_int) {
+        let mut buffer = [0u8; 4096];
+        loop {
+            // Orphans which exited by themselves aren't stragglers, so quietly reap those first.
+            while waitpid(-1, std::ptr::null_mut(), WNOHANG) > 0 {}
+
+            let fd = open(self.children.as_ptr(), O_RDONLY | O_CLOEXEC);
+            if fd == -1 {
+                return;
+            }
+            let len = read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len());
+            close(fd);
+            if len <= 0 {
+                return;
+            }
+            let children = &buffer[..len as usize];
+            for pid in Self::pids(children) {
+                let record = SupervisorRecord {
+                    kind: SupervisorRecord::STRAGGLER,
+                    pid,
+                    syscall: 0,
+                    comm: Self::comm(pid),
+                };
+                kill(pid, SIGKILL);
+                record.write(stragglers);
+            }
+            for pid in Self::pids(children) {
+                while waitpid(pid, std::ptr::null_mut(), 0) == -1 && *__errno_location() == EINTR {}
+            }
+        }
+    }
+
+    /// The pids in the contents of a `children` file. Each is followed by a space, so one cut
+    /// off by the end of the buffer is skipped, to be picked up on the next pass.
+    fn pids(children: &[u8]) -> impl Iterator<Item = c_int> + '_ {
+        children.split_inclusive(|b| *b == b' ').filter(|pid| pid.ends_with(b" ")).filter_map(
+            |pid| {
+                pid[..pid.len() - 1].iter().try_fold(0 as c_int, |n, digit| match digit {
+                    b'0'..=b'9' => n.checked_mul(10)?.checked_add((digit - b'0') as c_int),
+                    _ => None,
+                })
+            },
+        )
+    }
+
+    /// The name of process `pid`, without allocating.
+    unsafe fn comm(pid: c_int) -> [u8; 16] {
+        let mut comm = [0u8; 16];
+        let path = numbered_path(b"/proc/", pid, b"/comm");
+        let fd = open(path.as_ptr() as *const c_char, O_RDONLY | O_CLOEXEC);
+        if fd != -1 {
+            let read = read(fd, comm.as_mut_ptr() as *mut c_void, comm.len());
+            close(fd);
+            // Drop the trailing newline.
+            if read > 0 {
+                comm[read as usize - 1] = 0;
+            }
+        }
+        comm
+    }
+
+    /// Exit with `status`, as returned by waitpid, re-raising the signal if there was one.
+    unsafe fn exit_like(status: c_int) -> ! {
+        if WIFSIGNALED(status) {
+            let signal = WTERMSIG(status);
+            // The script may have dumped core already; there's no need for us to as well.
+            let no_core = rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            setrlimit(RLIMIT_CORE, &no_core);
+            let mut action = MaybeUninit::<sigaction>::zeroed().assume_init();
+            action.sa_sigaction = SIG_DFL;
+            sigaction(signal, &action, std::ptr::null_mut());
+            let mut mask = MaybeUninit::<sigset_t>::uninit();
+            sigemptyset(mask.as_mut_ptr());
+            sigaddset(mask.as_mut_ptr(), signal);
+            pthread_sigmask(SIG_UNBLOCK, mask.as_ptr(), std::ptr::null_mut());
+            kill(getpid(), signal);
+            _exit(128 + signal);
+        }
+        _exit(WEXITSTATUS(status));
+    }
+
+    /// Close everything above stderr bar the fds in `keep` (where -1 means none), so that no
+    /// fd the host opened without `O_CLOEXEC` (including another thread's pipes, mid-spawn)
+    /// leaks into the command.
+    unsafe fn close_inherited_fds(&self, keep: [c_int; 5]) {
+        unsafe fn close_range(first: c_int, last: c_int) -> bool {
+            first > last
+                || syscall(SYS_close_range, first as c_uint, last as c_uint, 0 as c_uint) == 0
+        }
+        let mut sorted = keep;
+        sorted.sort_unstable();
+        let mut first = 3;
+        let mut closed = true;
+        for fd in sorted {
+            if fd >= first {
+                closed &= close_range(first, fd - 1);
+                first = fd + 1;
+            }
+        }
+        if closed && close_range(first, c_int::MAX) {
+            return;
+        }
+        // close_range(2) only arrived in Linux 5.9.
+        for fd in (3..self.max_fd).filter(|fd| !keep.contains(fd)) {
+            close(fd);
+        }
+    }
+
+    /// Rust ignores SIGPIPE in the host, and exec carries ignored dispositions and the mask
+    /// over, so without this `yes | head -n1` would fail with EPIPE rather than exit quietly.
+    unsafe fn setup_signals(&self, inherited: &sigset_t) {
+        let mut action = MaybeUninit::<sigaction>::zeroed().assume_init();
+        if self.signals.reset {
+            action.sa_sigaction = SIG_DFL;
+            for signal in 1..=self.max_signal {
+                if signal != SIGKILL && signal != SIGSTOP {
+                    sigaction(signal, &action, std::ptr::null_mut());
+                }
+            }
+        }
+        action.sa_sigaction = SIG_IGN;
+        for signal in &self.signals.ignore {
+            sigaction(*signal, &action, std::ptr::null_mut());
+        }
+
+        let mut mask = *inherited;
+        if self.signals.reset {
+            sigemptyset(&mut mask);
+        }
+        for signal in &self.signals.block {
+            sigaddset(&mut mask, *signal);
+        }
+        pthread_sigmask(SIG_SETMASK, &mask, std::ptr::null_mut());
+    }
+
+    fn null_terminated(strings: &[CString]) -> Vec<*const c_char> {
+        strings.iter().map(|s| s.as_ptr()).chain(std::iter::once(std::ptr::null())).collect()
+    }
+}
+
+/// `prefix`, then `n` in decimal, then `suffix`, null terminated - without allocating, for
+/// building paths like `/proc/<pid>/comm` in the child. Anything too long is cut short.
+pub(crate) fn numbered_path(prefix: &[u8], n: c_int, suffix: &[u8]) -> [u8; 64] {
+    let mut digits = [0u8; 10];
+    let mut rest = n.unsigned_abs();
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (rest % 10) as u8;
+        len += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    let mut path = [0u8; 64];
+    let bytes = prefix.iter().chain(digits[..len].iter().rev()).chain(suffix);
+    for (i, b) in bytes.take(path.len() - 1).enumerate() {
+        path[i] = *b;
+    }
+    path
+}
+
+impl From<BashCommand> for ChildPlan {
+    fn from(command: BashCommand) -> Self {
+        Self::new(&command, &ChildOptions::default()).expect("The default options have no paths.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+
+    use super::{BashCommand, ChildOptions, ChildPlan};
+    use crate::{
+        error::SpawnStage,
+        process::{Process, ProcessError},
+    };
+
+    unsafe fn collect(ptrs: &[*const libc::c_char]) -> Vec<String> {
+        ptrs.iter()
+            .take_while(|p| !p.is_null())
+            .map(|p| CStr::from_ptr(*p).to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_child_plan_argv() -> anyhow::Result<()> {
+        let plan = ChildPlan::new(&BashCommand::new("echo hi")?, &ChildOptions::default())?;
+        assert_eq!(plan.argv_ptrs.last(), Some(&std::ptr::null()));
+        assert_eq!(
+            unsafe { collect(&plan.argv_ptrs) },
+            vec!["/usr/bin/env", "bash", "-c", "echo hi"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_child_plan_envp_is_a_snapshot_of_the_environment() -> anyhow::Result<()> {
+        let plan = ChildPlan::new(&BashCommand::new("true")?, &ChildOptions::default())?;
+        assert_eq!(plan.envp_ptrs.last(), Some(&std::ptr::null()));
+
+        let envp = unsafe { collect(&plan.envp_ptrs) };
+        assert_eq!(envp.len(), std::env::vars_os().count());
+        if let Ok(path) = std::env::var("PATH") {
+            assert!(envp.contains(&format!("PATH={path}")));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_numbered_path() {
+        let path = super::numbered_path(b"/proc/", 4071, b"/comm");
+        let path = CStr::from_bytes_until_nul(&path).unwrap();
+        assert_eq!(path.to_str(), Ok("/proc/4071/comm"));
+        let path = super::numbered_path(b"/proc/self/fd/", 0, b"");
+        assert_eq!(CStr::from_bytes_until_nul(&path).unwrap().to_str(), Ok("/proc/self/fd/0"));
+    }
+
+    #[test]
+    fn test_child_plan_reports_a_failed_exec() -> anyhow::Result<()> {
+        let mut plan = ChildPlan::new(&BashCommand::new("true")?, &ChildOptions::default())?;
+        plan.path = CString::new("/i/do/not/exist")?;
+
+        let mut process = Process::new();
+        let result = unsafe { process.open(plan) };
+        Ok(assert_eq!(
+            result.unwrap_err(),
+            ProcessError::SpawnFailed(SpawnStage::Exec, libc::ENOENT)
+        ))
+    }
+}
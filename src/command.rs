@@ -1,54 +1,1476 @@
-use std::ffi::{CString, NulError};
+use libc::{c_int, c_long, SIGKILL, SIGXCPU, SIGXFSZ};
+use std::{
+    ffi::{CString, NulError, OsStr, OsString},
+    io::{self, BufRead, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    child::{ChildOptions, ChildPlan, Limit},
+    error::{ErrorContext, RashError},
+    landlock::Landlock,
+    output::{Output, TracedCommand},
+    pipeline::Pipeline,
+    pool::SessionPool,
+    process::{OnDrop, Process, ProcessError, Stderr},
+    retry::{Retried, Retry},
+    sandbox::Sandbox,
+    seccomp::Seccomp,
+    session::Session,
+};
+
+/// A bash command, along with the options it should be run with.
+///
+/// [`rash!`](macro@crate::rash) is shorthand for `Command::new(script).output()`;
+/// build a `Command` yourself when you need control over how the script is run.
+///
+/// ```
+/// use rsbash::{Command, RashError};
+///
+/// pub fn configured() -> Result<(), RashError> {
+///     let output = Command::new("yes | head -n1").output()?;
+///     assert_eq!(output.ret_val, 0);
+///     assert_eq!(output.stdout, "y\n");
+///     assert_eq!(output.stderr, "");
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Command {
+    script: String,
+    child: ChildOptions,
+    redact: bool,
+    on_drop: OnDrop,
+    stderr: Stderr,
+    pipestatus: bool,
+    strict: bool,
+    trace: bool,
+    args: Vec<OsString>,
+    retry: Option<Retry>,
+}
+
+impl Command {
+    /// Create a new command which will run `script` with bash.
+    pub fn new<S: AsRef<str>>(script: S) -> Self {
+        Self {
+            script: script.as_ref().to_string(),
+            child: ChildOptions::default(),
+            redact: false,
+            on_drop: OnDrop::default(),
+            stderr: Stderr::default(),
+            pipestatus: false,
+            strict: false,
+            trace: false,
+            args: Vec::new(),
+            retry: None,
+        }
+    }
+
+    /// Whether to restore every signal disposition to its default and clear the signal mask
+    /// before running the script. Defaults to `true`.
+    ///
+    /// Rust ignores `SIGPIPE`, and ignored dispositions and the signal mask both survive exec,
+    /// so without this reset `yes | head -n1` would complain of a broken pipe instead of
+    /// quietly exiting as it does in a terminal. Set to `false` to have the script inherit the
+    /// dispositions and mask of the calling thread instead.
+    pub fn reset_signals(mut self, reset: bool) -> Self {
+        self.child.signals.reset = reset;
+        self
+    }
+
+    /// Start the script with `signal` ignored, as if by `trap '' <signal>`,
+    /// except that bash won't let the script undo it.
+    pub fn ignore_signal(mut self, signal: c_int) -> Self {
+        self.child.signals.ignore.push(signal);
+        self
+    }
+
+    /// Start the script with `signal` blocked.
+    pub fn block_signal(mut self, signal: c_int) -> Self {
+        self.child.signals.block.push(signal);
+        self
+    }
+
+    /// Pass `arg` to the script as its next positional parameter: `$1`, then `$2`, and so on.
+    ///
+    /// Unlike formatting a value into the script, an arg is never parsed by bash, so it's safe
+    /// to pass anything, e.g. a filename which might contain spaces, quotes or `$(...)`.
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Pass each of `args` to the script as its next positional parameter. See
+    /// [`Command::arg`].
+    pub fn args<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(mut self, args: I) -> Self {
+        self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// Run the script from within `dir`, rather than the current working directory.
+    ///
+    /// With [`Command::chroot`], `dir` is within the new root, and with [`Command::uid`],
+    /// the new user has to be allowed into it.
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.child.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the environment variable `key` to `value` for the script.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        let value = Some(value.as_ref().to_os_string());
+        self.child.env.push((key.as_ref().to_os_string(), value));
+        self
+    }
+
+    /// Remove the environment variable `key` from the script's environment.
+    pub fn env_remove<K: AsRef<OsStr>>(mut self, key: K) -> Self {
+        self.child.env.push((key.as_ref().to_os_string(), None));
+        self
+    }
+
+    /// Start the script with an empty environment, rather than a copy of ours, apart from any
+    /// variables set with [`Command::env`] after this. bash still sets a few of its own, such
+    /// as `PWD`, and a default `PATH`.
+    pub fn env_clear(mut self) -> Self {
+        self.child.env.clear();
+        self.child.env_clear = true;
+        self
+    }
+
+    /// Run the script with `dir` as its root directory, as with chroot(8), starting in the new
+    /// root unless [`Command::current_dir`] says otherwise. bash has to be found within it.
+    ///
+    /// This needs `CAP_SYS_CHROOT`, and is done before changing user.
+    pub fn chroot<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.child.chroot = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Run the script as the user `uid`, e.g. to run a hook as an unprivileged service user
+    /// from a process running as root. This needs `CAP_SETUID`, and `CAP_SETGID` as well for
+    /// the supplementary groups.
+    ///
+    /// Unless [`Command::groups`] is set too, the script has no supplementary groups. Clearing
+    /// them is what needs `CAP_SETGID`, and without it this fails with
+    /// [`SpawnStage::Setgroups`](crate::SpawnStage::Setgroups). Only the user id is changed:
+    /// the environment, including `HOME` and `USER`, is left as it is.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.child.uid = Some(uid);
+        self
+    }
+
+    /// Run the script with `gid` as its group. This needs `CAP_SETGID`.
+    ///
+    /// Unless [`Command::groups`] is set too, the script has no supplementary groups.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.child.gid = Some(gid);
+        self
+    }
+
+    /// Run the script with `groups` as its supplementary groups. This needs `CAP_SETGID`.
+    pub fn groups<I: IntoIterator<Item = u32>>(mut self, groups: I) -> Self {
+        self.child.groups = Some(groups.into_iter().collect());
+        self
+    }
+
+    /// Run the script with `mask` as its file mode creation mask, as with `umask`.
+    pub fn umask(mut self, mask: u32) -> Self {
+        self.child.umask = Some(mask);
+        self
+    }
+
+    /// Run the script with `nice` as its nice value, from -20 (highest priority) to 19
+    /// (lowest), as with nice(1). Going below the host's own needs `CAP_SYS_NICE`.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.child.nice = Some(nice);
+        self
+    }
+
+    /// Only let the script run on the CPUs numbered in `cpus`, as with taskset(1). Those which
+    /// don't exist are ignored, as long as at least one does.
+    pub fn cpu_affinity<I: IntoIterator<Item = usize>>(mut self, cpus: I) -> Self {
+        self.child.cpu_affinity = Some(cpus.into_iter().collect());
+        self
+    }
+
+    /// Kill and reap anything the script leaves running once it exits, such as a background
+    /// job it forgot about, reporting them in [`Output::stragglers`]. Defaults to `false`.
+    ///
+    /// Without this, a leftover `sleep infinity &` is reparented to init, and since it holds
+    /// the script's stdout open, the command never returns. With it, the script is run under
+    /// a supervisor process which is made a subreaper (see `PR_SET_CHILD_SUBREAPER` in
+    /// prctl(2)), so that orphans are reparented to it instead. Signals sent with
+    /// [`Child::kill`] still go to the script.
+    pub fn reap_orphans(mut self, reap: bool) -> Self {
+        self.child.reap_orphans = reap;
+        self
+    }
+
+    /// Relay any `SIGINT`, `SIGTERM` or `SIGHUP` the host receives while the command is running
+    /// to it, and return [`RashError::Interrupted`] once it stops. Defaults to `false`.
+    ///
+    /// Whether Ctrl-C reaches a command normally depends on which process group it ends up in.
+    /// With this, the command always gets its own process group, so the only signals it sees
+    /// are the ones the host relays. The host's own handlers for those signals are replaced
+    /// while any such command is running, and restored afterwards.
+    pub fn forward_signals(mut self, forward: bool) -> Self {
+        self.child.forward_signals = forward;
+        self
+    }
+
+    /// Cap a resource the command can use, as with `ulimit`, but without the script being
+    /// able to raise it again. See [`Limit`] for the units of `value` for each.
+    ///
+    /// If the command is stopped for going over its CPU time or file size limit, this returns
+    /// [`RashError::LimitExceeded`]. A script whose last command was stopped for it, rather
+    /// than bash itself, is only reported that way for the CPU time limit, once the command has
+    /// used that much; otherwise it just returns 128 plus the signal, as it would for `exit`.
+    pub fn limit(mut self, limit: Limit, value: u64) -> Self {
+        self.child.limits.push((limit, value));
+        self
+    }
+
+    /// Run the script isolated in its own namespaces. See [`Sandbox`] for what that means.
+    ///
+    /// If the namespaces can't be created, e.g. because user namespaces are disabled, this
+    /// returns [`RashError::SpawnFailed`] with [`SpawnStage::Unshare`](crate::SpawnStage::Unshare).
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.child.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Restrict what the script can do to the filesystem. See [`Landlock`] for details.
+    pub fn landlock(mut self, landlock: Landlock) -> Self {
+        self.child.landlock = Some(landlock);
+        self
+    }
+
+    /// Stop the script making particular system calls. See [`Seccomp`] for details.
+    pub fn seccomp(mut self, seccomp: Seccomp) -> Self {
+        self.child.seccomp = Some(seccomp);
+        self
+    }
+
+    /// Where the script's stderr goes. Defaults to [`Stderr::Capture`].
+    ///
+    /// [`Session`]s always capture it, as that's where they find the end of each command.
+    pub fn stderr(mut self, stderr: Stderr) -> Self {
+        self.stderr = stderr;
+        self
+    }
+
+    /// Report the return value of every stage of the last pipeline the script ran, like bash's
+    /// `PIPESTATUS`, in [`Output::pipestatus`]. Defaults to `false`.
+    ///
+    /// This is written down a pipe of its own from an `EXIT` trap, leaving stdout and stderr
+    /// as they are. A script which sets an `EXIT` trap of its own replaces ours, and so gets
+    /// no statuses back.
+    pub fn pipestatus(mut self, pipestatus: bool) -> Self {
+        self.pipestatus = pipestatus;
+        self
+    }
+
+    /// Run the script in bash's strict mode: `set -Eeuo pipefail` and
+    /// `shopt -s inherit_errexit`, so that the first command to fail stops it. Defaults to
+    /// `false`.
+    ///
+    /// An `ERR` trap notes the failing command's line, text and return value down the same
+    /// pipe as [`Command::pipestatus`], and [`Command::output`] returns them in a
+    /// [`RashError::ScriptFailed`], in place of the usual [`Output`]. A command which fails in
+    /// a subshell is reported as the subshell. The script still returns as usual if it calls
+    /// `exit` itself, or if bash gives up on it, e.g. for using an unset variable; and a script
+    /// which sets an `ERR` trap of its own replaces ours.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Trace the script with `set -x`, returning every command it ran, with the line it was
+    /// on and when it was run, in [`Output::trace`], or [`RashError::trace`] if it failed.
+    /// Defaults to `false`.
+    ///
+    /// bash writes the trace down a pipe of its own, through `BASH_XTRACEFD`, leaving stderr as
+    /// it is. A script which sets `PS4` or `BASH_XTRACEFD` itself, or which runs `set +x`,
+    /// cuts the trace short.
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Run the command again if it fails, as `retry` says. See [`Retry`].
+    ///
+    /// This applies to [`Command::output`], which returns the last attempt's result, and to
+    /// [`Command::retried`], which returns every attempt's. A command [spawned](Command::spawn)
+    /// or run in a [`Pipeline`] is only ever run once.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Keep the script out of any [`RashError`] this command returns, e.g. because it
+    /// contains credentials which mustn't end up in logs.
+    pub fn redact(mut self, redact: bool) -> Self {
+        self.redact = redact;
+        self
+    }
+
+    /// What to do with the child if its [`Child`] handle is dropped while it's still running,
+    /// e.g. because of a panic. Defaults to [`OnDrop::Kill`].
+    pub fn on_drop(mut self, on_drop: OnDrop) -> Self {
+        self.on_drop = on_drop;
+        self
+    }
+
+    /// Run the command to completion, collecting its return value, stdout and stderr.
+    ///
+    /// If bash returns 127 or 126, meaning that a command couldn't be found or couldn't be
+    /// executed, this returns [`RashError::CommandNotFound`] or
+    /// [`RashError::CommandNotExecutable`] respectively. In [strict mode](Command::strict), a
+    /// failing command returns [`RashError::ScriptFailed`].
+    ///
+    /// Any error returned carries the script (unless [redacted](Command::redact)),
+    /// the child's pid, how long it ran for, and whatever output it produced.
+    pub fn output(&self) -> Result<Output, RashError> {
+        match &self.retry {
+            Some(retry) => retry.run(self).into_result(),
+            None => self.spawn()?.wait(),
+        }
+    }
+
+    /// Run the command to completion, retrying it as [`Command::retry`] says, and return the
+    /// result and duration of every attempt. Without a retry policy, there's just the one.
+    pub fn retried(&self) -> Retried {
+        self.retry.clone().unwrap_or_else(|| Retry::new(1)).run(self)
+    }
+
+    /// Start a [`Pipeline`] which feeds this command's stdout into `next`'s stdin.
+    pub fn pipe(self, next: Command) -> Pipeline {
+        Pipeline::new(self).pipe(next)
+    }
+
+    /// Start a [`Pipeline`] which feeds this command's stdout into a stage written in Rust.
+    /// See [`Pipeline::pipe_with`].
+    pub fn pipe_with<F>(self, stage: F) -> Pipeline
+    where
+        F: Fn(&mut dyn BufRead, &mut dyn Write) -> io::Result<()> + Send + Sync + 'static,
+    {
+        Pipeline::new(self).pipe_with(stage)
+    }
+
+    /// Start a [`Session`]: a long-lived shell, spawned with this command's options, which
+    /// runs its script first - e.g. to `source` an environment file - then whatever commands
+    /// it's given. If the shell has to be restarted, the script is run again first.
+    ///
+    /// [`Command::strict`], [`Command::pipestatus`], [`Command::trace`] and [`Command::retry`]
+    /// only apply to a command run on its own, so a command with any of them set is rejected
+    /// with [`RashError::InvalidOption`]. Otherwise, returns the script's error, if it fails to
+    /// run.
+    pub fn session(&self) -> Result<Session, RashError> {
+        Session::start(self.clone())
+    }
+
+    /// Start a [`SessionPool`] of `size` sessions, each started as by [`Command::session`].
+    pub fn session_pool(&self, size: usize) -> Result<SessionPool, RashError> {
+        SessionPool::start(self.clone(), size)
+    }
+
+    /// Start the command running in the background, returning a handle to it.
+    pub fn spawn(&self) -> Result<Child, RashError> {
+        let mut child = Child {
+            process: self.process(),
+            command: self.description(),
+            start: Instant::now(),
+            limits: self.child.limits.clone(),
+            landlock_abi: None,
+            reports: (self.pipestatus || self.strict).then_some(0),
+            trace: self.trace.then_some((self.pipestatus || self.strict) as usize),
+        };
+        match self.open(&mut child) {
+            Ok(()) => Ok(child),
+            Err(e) => Err(child.error(e)),
+        }
+    }
+
+    /// Spawn bash running `script` with this command's options, for a [`Session`] to drive
+    /// through its stdio.
+    pub(crate) fn open_shell(&self, script: &str) -> Result<Process, RashError> {
+        let start = Instant::now();
+        let mut process = Process::new().on_drop(self.on_drop).streaming(true);
+        let opened = ChildPlan::new(&BashCommand::new(script)?.args(&self.args)?, &self.child)
+            .map_err(RashError::from)
+            .and_then(|plan| unsafe { process.open(plan) }.map_err(RashError::from));
+        match opened {
+            Ok(()) => Ok(process),
+            Err(e) => Err(e.with_context(ErrorContext {
+                command: Some(self.description()),
+                pid: process.pid(),
+                elapsed: Some(start.elapsed()),
+                ..ErrorContext::default()
+            })),
+        }
+    }
+
+    /// The script given to [`Command::new`].
+    pub(crate) fn script(&self) -> &str {
+        &self.script
+    }
+
+    /// Whether the script is to be kept out of errors.
+    pub(crate) fn redacted(&self) -> bool {
+        self.redact
+    }
+
+    /// The first option set which reports back down a side channel, if any: only
+    /// [`Command::output`] and [`Child`] read those.
+    pub(crate) fn side_channel_option(&self) -> Option<&'static str> {
+        [("strict", self.strict), ("pipestatus", self.pipestatus), ("trace", self.trace)]
+            .into_iter()
+            .find_map(|(name, set)| set.then_some(name))
+    }
+
+    /// Whether the command is to be run again if it fails.
+    pub(crate) fn retries(&self) -> bool {
+        self.retry.is_some()
+    }
+
+    /// The script, unless it's to be kept out of errors.
+    pub(crate) fn description(&self) -> String {
+        match self.redact {
+            true => "<redacted>".to_string(),
+            false => self.script.clone(),
+        }
+    }
+
+    /// A process for this command to be spawned into by [`Command::open_process`].
+    pub(crate) fn process(&self) -> Process {
+        Process::new().on_drop(self.on_drop).stderr_to(self.stderr)
+    }
+
+    /// Spawn bash running the script with this command's options into `process`, returning the
+    /// Landlock ABI it was restricted with, if any.
+    pub(crate) fn open_process(&self, process: &mut Process) -> Result<Option<u32>, RashError> {
+        // On the script's first line, so as not to throw its line numbers out. Each trap writes
+        // a tag and then its fields, each ended by a NUL, for `Reports` to read back.
+        // The reports' side channel comes first, then the trace's, as `Child` expects.
+        let mut script = String::new();
+        let side = match self.pipestatus || self.strict {
+            true => process.side_channel()?,
+            false => -1,
+        };
+        if self.strict {
+            script += "set -Eeuo pipefail; shopt -s inherit_errexit; ";
+            script += &format!(
+                "trap 'builtin printf \"err\\0%s\\0%s\\0%s\\0\" \"$?\" \"$LINENO\" \
+                 \"$BASH_COMMAND\" >&{side}' ERR; "
+            );
+        }
+        if self.pipestatus {
+            script += &format!(
+                "trap 'builtin printf \"pipestatus\\0%s\\0\" \"${{PIPESTATUS[*]}}\" >&{side}' EXIT; "
+            );
+        }
+        if self.trace {
+            script += &format!(
+                "BASH_XTRACEFD={}; PS4='{}'; set -x; ",
+                process.side_channel()?,
+                TracedCommand::PS4
+            );
+        }
+        script += &self.script;
+        let plan = ChildPlan::new(&BashCommand::new(&script)?.args(&self.args)?, &self.child)?;
+        let landlock_abi = plan.landlock_abi();
+        unsafe { process.open(plan)? };
+        Ok(landlock_abi)
+    }
+
+    fn open(&self, child: &mut Child) -> Result<(), RashError> {
+        child.landlock_abi = self.open_process(&mut child.process)?;
+        Ok(())
+    }
+}
+
+/// A running [`Command`], as returned by [`Command::spawn`].
+///
+/// If a `Child` is dropped before it has been waited on, the process is dealt with according
+/// to [`Command::on_drop`]: by default, it's killed and reaped.
+pub struct Child {
+    process: Process,
+    command: String,
+    start: Instant,
+    limits: Vec<(Limit, u64)>,
+    landlock_abi: Option<u32>,
+    /// Which of the process's side channels the traps report down, if any.
+    reports: Option<usize>,
+    /// Which of the process's side channels bash traces down, if any.
+    trace: Option<usize>,
+}
+
+impl Child {
+    /// The pid of the child process.
+    pub fn pid(&self) -> c_int {
+        self.process.pid().unwrap_or(-1)
+    }
+
+    /// Kill the child with `SIGKILL`. It still needs to be [waited](Child::wait) on.
+    ///
+    /// With [`Command::reap_orphans`], anything the script left running is killed too.
+    pub fn kill(&mut self) -> Result<(), RashError> {
+        self.process.kill(SIGKILL).map_err(|e| self.error(e.into()))
+    }
+
+    /// Wait for the child to exit, collecting its return value, stdout and stderr.
+    ///
+    /// See [`Command::output`] for the errors this can return.
+    pub fn wait(mut self) -> Result<Output, RashError> {
+        self.close().map_err(|e| self.error(e))
+    }
+
+    fn close(&mut self) -> Result<Output, RashError> {
+        let closed = unsafe { self.process.close() };
+        // Whatever else went wrong, will have been because of this.
+        if let Some((pid, syscall, name)) = self.process.blocked_syscall() {
+            return Err(RashError::SyscallBlocked {
+                syscall: syscall as c_long,
+                pid,
+                name,
+                context: Box::default(),
+            });
+        }
+        let ret_val = match closed {
+            Ok(ret_val) => ret_val,
+            Err(ProcessError::OpenDidNotCloseNormally(signal)) => {
+                return Err(self
+                    .limit_exceeded(signal)
+                    .unwrap_or_else(|| ProcessError::OpenDidNotCloseNormally(signal).into()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // bash reports its last command being killed by a signal as 128 + the signal, but so
+        // could the script's own `exit`, so that's only put down to the CPU limit if the
+        // command has actually used its time up.
+        if matches!(ret_val - 128, SIGXCPU | SIGKILL) && self.cpu_time_used_up() {
+            return Err(RashError::LimitExceeded {
+                limit: Limit::Cpu,
+                context: Box::default(),
+            });
+        }
+        let reports = Reports::parse(self.side(self.reports));
+        let output = Output {
+            ret_val,
+            stdout: self.process.stdout()?,
+            stderr: self.process.stderr()?,
+            stragglers: self.process.stragglers(),
+            pipestatus: reports.pipestatus,
+            trace: self.trace.map(|_| self.traced()),
+            usage: self.process.usage(),
+            started: self.process.started(),
+            finished: self.process.finished(),
+            landlock_abi: self.landlock_abi,
+        };
+        // Commands failing inside `$(...)` can leave reports behind without stopping the script.
+        if let Some((_, line, command)) = reports.failure.filter(|_| output.ret_val != 0) {
+            return Err(RashError::ScriptFailed {
+                ret_val: output.ret_val,
+                line,
+                command,
+                context: Box::default(),
+            });
+        }
+        match output.ret_val {
+            126 => Err(RashError::CommandNotExecutable {
+                message: output.stderr,
+                context: Box::default(),
+            }),
+            127 => Err(RashError::CommandNotFound {
+                message: output.stderr,
+                context: Box::default(),
+            }),
+            _ => Ok(output),
+        }
+    }
+
+    /// The error for the command having been killed by `signal`, if that was down to one of
+    /// its limits.
+    fn limit_exceeded(&self, signal: c_int) -> Option<RashError> {
+        let limit = match signal {
+            SIGXCPU => Limit::Cpu,
+            SIGXFSZ => Limit::FileSize,
+            SIGKILL if self.cpu_time_used_up() => Limit::Cpu,
+            _ => return None,
+        };
+        self.limits.iter().any(|(l, _)| *l == limit).then(|| RashError::LimitExceeded {
+            limit,
+            context: Box::default(),
+        })
+    }
+
+    /// Whether the command has a CPU time limit, and has used at least that much.
+    fn cpu_time_used_up(&self) -> bool {
+        let usage = self.process.usage();
+        self.limits.iter().any(|(limit, secs)| {
+            *limit == Limit::Cpu
+                && usage.user_time + usage.system_time >= Duration::from_secs(*secs)
+        })
+    }
+
+    fn error(&self, e: RashError) -> RashError {
+        let (stdout, stderr) = self.process.partial_output();
+        e.with_context(ErrorContext {
+            command: Some(self.command.clone()),
+            pid: self.process.pid(),
+            elapsed: Some(self.start.elapsed()),
+            stdout,
+            stderr,
+            trace: self.traced(),
+        })
+    }
+
+    /// Everything that came down side channel `n`, if there is one.
+    fn side(&self, n: Option<usize>) -> &[u8] {
+        n.and_then(|n| self.process.side(n)).unwrap_or_default()
+    }
+
+    /// The commands the script was traced running, bar the traps' own.
+    fn traced(&self) -> Vec<TracedCommand> {
+        let traced = TracedCommand::parse(self.side(self.trace));
+        traced.into_iter().filter(|traced| !Reports::is_trap(&traced.command)).collect()
+    }
+}
+
+/// What the traps set by [`Command::pipestatus`] and [`Command::strict`] wrote down the side
+/// channel. Nothing at all if a trap didn't run, e.g. because the script replaced it.
+#[derive(Debug, Default)]
+struct Reports {
+    pipestatus: Option<Vec<i32>>,
+    /// The return value, line and text of the last command to fail.
+    failure: Option<(c_int, u32, String)>,
+}
+
+impl Reports {
+    /// Whether `command`, as traced by `set -x`, is one of the traps writing a report.
+    fn is_trap(command: &str) -> bool {
+        ["err", "pipestatus"]
+            .iter()
+            .any(|tag| command.starts_with(&format!("builtin printf '{tag}\\0%s\\0")))
+    }
+
+    fn parse(side: &[u8]) -> Self {
+        let mut reports = Reports::default();
+        let mut fields = side.split(|&b| b == 0).map(String::from_utf8_lossy);
+        while let Some(tag) = fields.next() {
+            match &*tag {
+                "pipestatus" => {
+                    reports.pipestatus = fields.next().and_then(|statuses| {
+                        statuses.split_whitespace().map(|status| status.parse().ok()).collect()
+                    })
+                }
+                "err" => {
+                    let (ret_val, line, command) = (fields.next(), fields.next(), fields.next());
+                    reports.failure = ret_val.zip(line).zip(command).and_then(|((r, l), c)| {
+                        Some((r.parse().ok()?, l.parse().ok()?, c.into_owned()))
+                    })
+                }
+                // Including the empty field after the last NUL.
+                _ => break,
+            }
+        }
+        reports
+    }
+}
+
+lazy_static! {
+    static ref ENV: CString = CString::new("/usr/bin/env").expect("/usr/bin/env CString failed.");
+    static ref BASH: CString = CString::new("bash").expect("bash CString failed.");
+    static ref COMMAND: CString = CString::new("-c").expect("-c CString failed.");
+}
 
 #[derive(Debug)]
 pub(crate) struct BashCommand {
-    command: CString,
+    script: CString,
+    args: Vec<CString>,
 }
 
 impl BashCommand {
     pub fn new<S: AsRef<str>>(s: S) -> Result<Self, NulError> {
-        let quoted = Self::quote(s.as_ref());
         Ok(Self {
-            command: CString::new(Self::format(quoted))?,
+            script: CString::new(s.as_ref())?,
+            args: Vec::new(),
         })
     }
 
-    pub fn command(&self) -> CString {
-        self.command.clone()
+    /// Pass `args` to the script as its positional parameters.
+    pub fn args(mut self, args: &[OsString]) -> Result<Self, NulError> {
+        for arg in args {
+            self.args.push(CString::new(arg.as_bytes())?);
+        }
+        Ok(self)
     }
 
-    fn format(s: String) -> String {
-        format!("/usr/bin/env bash -c {}", s)
-    }
-
-    fn quote(s: &str) -> String {
-        shell_words::quote(s).to_string()
+    /// The argv to exec, i.e. `/usr/bin/env bash -c <script>`, followed by `bash` and the
+    /// args if there are any, as bash takes the first argument after the script as `$0`.
+    ///
+    /// bash is exec'd directly rather than via `/bin/sh -c`, so there's no quoting to get
+    /// wrong, and no intermediate shell to undo the signal setup done in the child
+    /// (dash, for one, clears the signal mask on startup).
+    pub fn argv(&self) -> Vec<CString> {
+        let mut argv = vec![ENV.clone(), BASH.clone(), COMMAND.clone(), self.script.clone()];
+        if !self.args.is_empty() {
+            argv.push(BASH.clone());
+            argv.extend(self.args.iter().cloned());
+        }
+        argv
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BashCommand;
+    use std::time::{Duration, Instant};
 
-    #[test]
-    fn test_bash_command_formats_correctly() {
-        let input = String::from("hi");
-        let expected = String::from("/usr/bin/env bash -c hi");
-        assert_eq!(BashCommand::format(input), expected);
+    use super::{BashCommand, Command, Limit};
+    use crate::{
+        error::SpawnStage,
+        output::TracedCommand,
+        process::{OnDrop, Stderr},
+        RashError,
+    };
+
+    fn argv(command: &BashCommand) -> Vec<String> {
+        command.argv().into_iter().map(|s| s.into_string().unwrap()).collect()
     }
 
     #[test]
-    fn test_bash_command_quotes_correctly() {
-        assert_eq!(BashCommand::quote("hi"), "hi".to_string());
+    fn test_bash_command_argv() -> anyhow::Result<()> {
+        let command = BashCommand::new("hi")?;
+        Ok(assert_eq!(argv(&command), vec!["/usr/bin/env", "bash", "-c", "hi"]))
+    }
 
+    #[test]
+    fn test_bash_command_passes_quotes_through_verbatim() -> anyhow::Result<()> {
         let input = "\"\"'blah' \'blah\' 'blah'''";
-        let expected = "'\"\"'\\''blah'\\'' '\\''blah'\\'' '\\''blah'\\'''\\'''\\'''";
-        assert_eq!(BashCommand::quote(input), expected.to_string());
+        let command = BashCommand::new(input)?;
+        Ok(assert_eq!(argv(&command)[3], input))
+    }
+
+    #[test]
+    fn test_bash_command_passes_special_characters_through_verbatim() -> anyhow::Result<()> {
+        let input = "echo \"$HOME\" `pwd` \\n *.rs ~ {a,b} | cat; x=1 && y=2 || z=3 # ünïcödé\n\t";
+        let command = BashCommand::new(input)?;
+        Ok(assert_eq!(argv(&command)[3], input))
+    }
+
+    #[test]
+    fn test_command_runs_special_characters_as_written() -> Result<(), RashError> {
+        let script = r#"x='a  b'; printf '%s|' "$x" $x '$x' "\$x" 'it'\''s' "say \"hi\"" \\ {1,2}"#;
+        let output = Command::new(script).output()?;
+        Ok(assert_eq!(output.stdout, r#"a  b|a|b|$x|$x|it's|say "hi"|\|1|2|"#))
+    }
+
+    #[test]
+    fn test_bash_command_argv_with_args() -> anyhow::Result<()> {
+        let command = BashCommand::new("hi")?.args(&["a b".into(), "$c".into()])?;
+        Ok(assert_eq!(
+            argv(&command),
+            vec!["/usr/bin/env", "bash", "-c", "hi", "bash", "a b", "$c"]
+        ))
+    }
+
+    #[test]
+    fn test_bash_command_rejects_null_bytes() {
+        assert_eq!(BashCommand::new("echo \0").unwrap_err().nul_position(), 5);
+    }
+
+    #[test]
+    fn test_command_resets_sigpipe() -> Result<(), RashError> {
+        let output = Command::new("yes | head -n1").output()?;
+        Ok(assert_eq!(
+            (output.ret_val, output.stdout.as_str(), output.stderr.as_str()),
+            (0, "y\n", "")
+        ))
+    }
+
+    /// Parse the `SigIgn`/`SigBlk` bitmask of whichever process the script ends up exec'ing.
+    fn signal_set(command: Command, field: &str) -> Result<u64, RashError> {
+        let output = command.output()?;
+        let line = output.stdout.lines().find(|l| l.starts_with(field)).unwrap().to_string();
+        Ok(u64::from_str_radix(line.split_whitespace().last().unwrap(), 16).unwrap())
+    }
+
+    fn bit(signal: libc::c_int) -> u64 {
+        1 << (signal - 1)
+    }
+
+    // glibc reserves signals 32 and 33 for itself and won't let us touch them.
+    const GLIBC_RESERVED: u64 = 0b11 << 31;
+
+    #[test]
+    fn test_command_clears_ignored_and_blocked_signals() -> Result<(), RashError> {
+        let status = || Command::new("cat /proc/self/status");
+        assert_eq!(signal_set(status(), "SigIgn")? & !GLIBC_RESERVED, 0);
+        assert_eq!(signal_set(status(), "SigBlk")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_without_signal_reset_inherits_sigpipe() -> Result<(), RashError> {
+        let command = Command::new("cat /proc/self/status").reset_signals(false);
+        Ok(assert_ne!(signal_set(command, "SigIgn")? & bit(libc::SIGPIPE), 0))
+    }
+
+    #[test]
+    fn test_command_ignore_signal() -> Result<(), RashError> {
+        let output =
+            Command::new("kill -TERM $$; echo -n alive").ignore_signal(libc::SIGTERM).output()?;
+        Ok(assert_eq!((output.ret_val, output.stdout.as_str()), (0, "alive")))
+    }
+
+    #[test]
+    fn test_command_block_signal() -> Result<(), RashError> {
+        let command = Command::new("cat /proc/self/status")
+            .block_signal(libc::SIGUSR1)
+            .block_signal(libc::SIGTERM);
+        Ok(assert_eq!(signal_set(command, "SigBlk")?, bit(libc::SIGUSR1) | bit(libc::SIGTERM)))
+    }
+
+    #[test]
+    fn test_command_current_dir() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let output = Command::new("pwd").current_dir(dir.path()).output()?;
+        Ok(assert_eq!(output.stdout.trim_end(), dir.path().canonicalize()?.to_str().unwrap()))
+    }
+
+    #[test]
+    fn test_command_args() -> Result<(), RashError> {
+        let command = Command::new("echo \"$0 $# [$1] [$2]\"").arg("a b").args(["$(echo no)"]);
+        Ok(assert_eq!(command.output()?.stdout, "bash 2 [a b] [$(echo no)]\n"))
+    }
+
+    #[test]
+    fn test_command_env() -> Result<(), RashError> {
+        let command = Command::new("echo \"$GREETING ${HOME-unset}\"; env | grep -c '^PATH='");
+        let output = command.env("GREETING", "hi").env_remove("HOME").output()?;
+        Ok(assert_eq!(output.stdout, "hi unset\n1\n"))
+    }
+
+    #[test]
+    fn test_command_env_clear() -> Result<(), RashError> {
+        let command = Command::new("echo \"${HOME-unset} $GREETING\"").env("HOME", "/nowhere");
+        let output = command.env_clear().env("GREETING", "hi").output()?;
+        Ok(assert_eq!(output.stdout, "unset hi\n"))
+    }
+
+    #[test]
+    fn test_command_env_rejects_null_bytes() {
+        let error = Command::new("true").env("GREETING", "h\0i").output().unwrap_err();
+        assert!(matches!(error, RashError::NullByteInCommand { .. }));
+    }
+
+    #[test]
+    fn test_command_stderr() -> Result<(), RashError> {
+        let script = "echo out; echo err >&2";
+        let output = Command::new(script).stderr(Stderr::Null).output()?;
+        assert_eq!((output.stdout.as_str(), output.stderr.as_str()), ("out\n", ""));
+        let output = Command::new(script).stderr(Stderr::Stdout).output()?;
+        Ok(assert_eq!((output.stdout.as_str(), output.stderr.as_str()), ("out\nerr\n", "")))
+    }
+
+    #[test]
+    fn test_command_pipestatus() -> Result<(), RashError> {
+        let output = Command::new("true | false | true").pipestatus(true).output()?;
+        assert_eq!((output.ret_val, output.pipestatus), (0, Some(vec![0, 1, 0])));
+        let output = Command::new("echo hi | (exit 3)\nexit 4").pipestatus(true).output()?;
+        assert_eq!((output.ret_val, output.pipestatus), (4, Some(vec![0, 3])));
+        Ok(assert_eq!((output.stdout, output.stderr), (String::new(), String::new())))
+    }
+
+    #[test]
+    fn test_command_pipestatus_keeps_line_numbers() -> Result<(), RashError> {
+        let output = Command::new("true\necho $LINENO").pipestatus(true).output()?;
+        Ok(assert_eq!(output.stdout, "2\n"))
+    }
+
+    #[test]
+    fn test_command_pipestatus_when_supervised() -> Result<(), RashError> {
+        let command = Command::new("sleep 10 & false | true").reap_orphans(true);
+        Ok(assert_eq!(command.pipestatus(true).output()?.pipestatus, Some(vec![1, 0])))
+    }
+
+    #[test]
+    fn test_command_pipestatus_is_missing() -> Result<(), RashError> {
+        assert_eq!(Command::new("false | true").output()?.pipestatus, None);
+        let command = Command::new("trap 'echo bye' EXIT; false | true").pipestatus(true);
+        Ok(assert_eq!(command.output()?.pipestatus, None))
+    }
+
+    fn failure(command: Command) -> Option<(i32, u32, String)> {
+        match command.output() {
+            Err(RashError::ScriptFailed {
+                ret_val,
+                line,
+                command,
+                ..
+            }) => Some((ret_val, line, command)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_command_strict() -> Result<(), RashError> {
+        assert_eq!(Command::new("false\necho hi").output()?.stdout, "hi\n");
+        let command = Command::new("echo hi\nls /nope\necho bye").strict(true);
+        assert_eq!(failure(command), Some((2, 2, "ls /nope".to_string())));
+        let command = Command::new("f() {\n  false\n}\nf").strict(true);
+        assert_eq!(failure(command), Some((1, 2, "false".to_string())));
+        let command = Command::new("true\nfalse | true").strict(true);
+        Ok(assert_eq!(failure(command), Some((1, 2, "true".to_string()))))
+    }
+
+    #[test]
+    fn test_command_strict_reports_commands_not_found() {
+        let command = Command::new("true\ni_am_not_a_valid_executable").strict(true);
+        let failure = failure(command);
+        assert_eq!(failure, Some((127, 2, "i_am_not_a_valid_executable".to_string())));
+    }
+
+    #[test]
+    fn test_command_strict_ignores_handled_failures() -> Result<(), RashError> {
+        let script = "if false; then :; fi\nfalse || true\necho $(false; echo hi)";
+        Ok(assert_eq!(Command::new(script).strict(true).output()?.stdout, "\n"))
+    }
+
+    #[test]
+    fn test_command_strict_returns_exits() -> Result<(), RashError> {
+        assert_eq!(Command::new("exit 3").strict(true).output()?.ret_val, 3);
+        let output = Command::new("echo $unset\necho hi").strict(true).output()?;
+        assert_eq!((output.ret_val, output.stdout), (1, "".to_string()));
+        assert!(output.stderr.contains("unset: unbound variable"));
+        let output = Command::new("trap 'echo bye' ERR; false").strict(true).output()?;
+        Ok(assert_eq!((output.ret_val, output.stdout), (1, "bye\n".to_string())))
+    }
+
+    #[test]
+    fn test_command_strict_with_pipestatus() -> Result<(), RashError> {
+        let command = Command::new("true | true\nfalse").strict(true).pipestatus(true);
+        assert_eq!(failure(command), Some((1, 2, "false".to_string())));
+        let output = Command::new("true | true").strict(true).pipestatus(true).output()?;
+        Ok(assert_eq!((output.ret_val, output.pipestatus), (0, Some(vec![0, 0]))))
+    }
+
+    fn traced(trace: &[TracedCommand]) -> Vec<(u32, usize, &str)> {
+        trace.iter().map(|t| (t.line, t.depth, t.command.as_str())).collect()
+    }
+
+    #[test]
+    fn test_command_trace() -> Result<(), RashError> {
+        assert_eq!(Command::new("echo hi").output()?.trace, None);
+        let script = "echo hi\nx=$(echo 1)\necho \"$x\" >&2\nf() {\n  echo 'a\nb'\n}\nf";
+        let output = Command::new(script).trace(true).output()?;
+        assert_eq!((output.stdout.as_str(), output.stderr.as_str()), ("hi\na\nb\n", "1\n"));
+        let trace = output.trace.unwrap();
+        let expected =
+            [(1, 1, "echo hi"), (2, 2, "echo 1"), (2, 1, "x=1"), (3, 1, "echo 1"), (8, 1, "f")];
+        assert_eq!(traced(&trace[..5]), expected);
+        assert_eq!((trace[5].depth, trace[5].command.as_str()), (1, "echo 'a\nb'"));
+        assert!(trace.windows(2).all(|pair| pair[0].time <= pair[1].time));
+        Ok(assert!(output.started <= trace[0].time && trace[5].time <= output.finished))
+    }
+
+    #[test]
+    fn test_command_trace_leaves_out_the_traps() -> Result<(), RashError> {
+        let command = Command::new("true | true\nfalse").strict(true).pipestatus(true);
+        let error = command.trace(true).output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::ScriptFailed {
+                line: 2,
+                ..
+            }
+        ));
+        assert_eq!(traced(error.trace()), [(1, 1, "true"), (1, 1, "true"), (2, 1, "false")]);
+        let output = Command::new("true | false").pipestatus(true).trace(true).output()?;
+        assert_eq!(output.pipestatus, Some(vec![0, 1]));
+        Ok(assert_eq!(traced(&output.trace.unwrap()), [(1, 1, "true"), (1, 1, "false")]))
+    }
+
+    #[test]
+    fn test_command_reports_a_failed_chdir() {
+        let error = Command::new("pwd").current_dir("/i/do/not/exist").output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Chdir,
+                errno: libc::ENOENT,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_command_not_found() {
+        let error = Command::new("i_am_not_a_valid_executable").output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::CommandNotFound { message, .. } if message.contains("command not found")
+        ));
+    }
+
+    #[test]
+    fn test_command_not_executable() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        std::fs::write(dir.path().join("foo.sh"), "echo hi")?;
+        let error = Command::new("./foo.sh").current_dir(dir.path()).output().unwrap_err();
+        Ok(assert!(matches!(
+            error,
+            RashError::CommandNotExecutable { message, .. } if message.contains("Permission denied")
+        )))
+    }
+
+    #[test]
+    fn test_command_killed_by_signal() {
+        let error = Command::new("kill -KILL $$").output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::KilledBySignal {
+                signal: libc::SIGKILL,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_command_errors_carry_context() {
+        let error =
+            Command::new("echo -n partial; echo -n oops >&2; kill -KILL $$").output().unwrap_err();
+        assert_eq!(error.command(), Some("echo -n partial; echo -n oops >&2; kill -KILL $$"));
+        assert!(error.pid().is_some());
+        assert!(error.elapsed().is_some());
+        assert_eq!(error.stdout(), "partial");
+        assert_eq!(error.stderr(), "oops");
+
+        let display = error.to_string();
+        assert!(display.starts_with("Command was killed by signal 9 [command: "));
+        assert!(display.contains(&format!("pid: {}", error.pid().unwrap())));
+        assert!(display.ends_with(r#"stdout: "partial", stderr: "oops"]"#));
+    }
+
+    #[test]
+    fn test_command_errors_before_spawning_have_no_pid() {
+        let error = Command::new("echo \0").output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::NullByteInCommand {
+                pos: 5,
+                ..
+            }
+        ));
+        assert_eq!(error.pid(), None);
+        assert_eq!(error.command(), Some("echo \0"));
+    }
+
+    #[test]
+    fn test_command_redact() {
+        let error = Command::new("exit 127 # secret").redact(true).output().unwrap_err();
+        assert_eq!(error.command(), Some("<redacted>"));
+        assert!(!error.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn test_command_partial_output_survives_invalid_utf8() {
+        let error = Command::new(r"printf 'hi\xff'").output().unwrap_err();
+        assert!(matches!(error, RashError::FailedToReadStdout { .. }));
+        assert_eq!(error.stdout(), "hi\u{FFFD}");
+    }
+
+    /// When `pid` started, in clock ticks since boot, if it's still around, even as a zombie.
+    fn start_time(pid: libc::c_int) -> Option<u64> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // The name, in brackets, can have spaces in it, so count the fields from its end.
+        stat.rsplit_once(')')?.1.split_whitespace().nth(19)?.parse().ok()
+    }
+
+    /// Whether the process `pid`, which started at `start_time`, has been reaped. A zombie
+    /// is still there, but a new process which has reused the pid isn't it.
+    fn reaped(pid: libc::c_int, start_time: u64) -> bool {
+        self::start_time(pid) != Some(start_time)
+    }
+
+    #[test]
+    fn test_child_wait() -> Result<(), RashError> {
+        let child = Command::new("echo -n hi").spawn()?;
+        assert!(child.pid() > 0);
+        Ok(assert_eq!(child.wait()?.stdout, "hi"))
+    }
+
+    #[test]
+    fn test_child_kill() -> Result<(), RashError> {
+        let mut child = Command::new("sleep 100").spawn()?;
+        child.kill()?;
+        let error = child.wait().unwrap_err();
+        Ok(assert!(matches!(
+            error,
+            RashError::KilledBySignal {
+                signal: libc::SIGKILL,
+                ..
+            }
+        )))
     }
 
     #[test]
-    fn test_bash_command_formats_cstring_correctly() -> anyhow::Result<()> {
-        let command = BashCommand::new("hello")?.command();
-        Ok(assert_eq!(command.into_string()?, "/usr/bin/env bash -c hello".to_string()))
+    fn test_child_kill_with_reap_orphans_kills_background_jobs() -> Result<(), RashError> {
+        let mut child = Command::new("sleep 100 & sleep 100").reap_orphans(true).spawn()?;
+        std::thread::sleep(Duration::from_millis(100));
+        child.kill()?;
+        let error = child.wait().unwrap_err();
+        Ok(assert!(matches!(
+            error,
+            RashError::KilledBySignal {
+                signal: libc::SIGKILL,
+                ..
+            }
+        )))
+    }
+
+    #[test]
+    fn test_reap_orphans_kills_stragglers() -> Result<(), RashError> {
+        let start = Instant::now();
+        let script = "sleep infinity & echo -n hi; cut -d' ' -f22 /proc/$!/stat >&2";
+        let output = Command::new(script).reap_orphans(true).output()?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!((output.ret_val, output.stdout.as_str()), (0, "hi"));
+        assert_eq!(output.stragglers.len(), 1);
+        assert_eq!(output.stragglers[0].name, "sleep");
+        let start_time = output.stderr.trim().parse().unwrap();
+        Ok(assert!(reaped(output.stragglers[0].pid, start_time)))
+    }
+
+    #[test]
+    fn test_reap_orphans_kills_double_forked_daemons() -> Result<(), RashError> {
+        let output =
+            Command::new("(sleep infinity &); (sleep infinity &)").reap_orphans(true).output()?;
+        // Either daemon may be killed before its subshell has got as far as exec'ing sleep.
+        assert_eq!(output.stragglers.len(), 2);
+        Ok(assert!(output.stragglers.iter().all(|s| ["sleep", "bash"].contains(&s.name.as_str()))))
+    }
+
+    #[test]
+    fn test_reap_orphans_ignores_orphans_which_already_exited() -> Result<(), RashError> {
+        let output =
+            Command::new("(sleep 0 &); sleep 0.2; echo done").reap_orphans(true).output()?;
+        assert_eq!(output.stdout, "done\n");
+        Ok(assert_eq!(output.stragglers, vec![]))
+    }
+
+    #[test]
+    fn test_reap_orphans_keeps_the_scripts_exit() -> Result<(), RashError> {
+        assert_eq!(Command::new("exit 3").reap_orphans(true).output()?.ret_val, 3);
+        let error = Command::new("kill -TERM $$").reap_orphans(true).output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::KilledBySignal {
+                signal: libc::SIGTERM,
+                ..
+            }
+        ));
+        let error = Command::new("i-do-not-exist").reap_orphans(true).output().unwrap_err();
+        Ok(assert!(matches!(error, RashError::CommandNotFound { .. })))
+    }
+
+    #[test]
+    fn test_reap_orphans_reports_a_failed_chdir() {
+        let error = Command::new("true")
+            .current_dir("/i/do/not/exist")
+            .reap_orphans(true)
+            .output()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Chdir,
+                errno: libc::ENOENT,
+                ..
+            }
+        ));
+    }
+
+    /// Held by every test which forwards signals: a signal raised in one reaches every other's
+    /// command, and would kill the test binary if another dropped the last registration first.
+    static FORWARDING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn test_forward_signals_runs_the_command_in_its_own_process_group() -> Result<(), RashError> {
+        let _forwarding = FORWARDING.lock().unwrap_or_else(|e| e.into_inner());
+        let child = Command::new("cut -d' ' -f5 /proc/$$/stat").forward_signals(true).spawn()?;
+        let pid = child.pid();
+        Ok(assert_eq!(child.wait()?.stdout, format!("{pid}\n")))
+    }
+
+    #[test]
+    fn test_forward_signals_relays_sigint_and_reports_the_interruption() -> anyhow::Result<()> {
+        let _forwarding = FORWARDING.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::TempDir::new()?;
+        let script = "echo started; touch started; sleep 100";
+        let child = Command::new(script).current_dir(dir.path()).forward_signals(true).spawn()?;
+        let start = Instant::now();
+        while !dir.path().join("started").exists() {
+            assert!(start.elapsed() < Duration::from_secs(10), "the command never started");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        unsafe { libc::raise(libc::SIGINT) };
+        let error = child.wait().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::Interrupted {
+                signal: libc::SIGINT,
+                ..
+            }
+        ));
+        Ok(assert_eq!(error.stdout(), "started\n"))
+    }
+
+    #[test]
+    fn test_forward_signals_relays_each_signal_once_under_a_supervisor() -> anyhow::Result<()> {
+        let _forwarding = FORWARDING.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::TempDir::new()?;
+        let script = "trap 'echo >> interrupts' INT; touch started
+            until [ -e interrupts ]; do sleep 0.05; done; sleep 0.5";
+        let command = Command::new(script).current_dir(dir.path()).forward_signals(true);
+        let child = command.reap_orphans(true).spawn()?;
+        let start = Instant::now();
+        while !dir.path().join("started").exists() {
+            assert!(start.elapsed() < Duration::from_secs(10), "the command never started");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        unsafe { libc::raise(libc::SIGINT) };
+        assert!(child.wait().is_err());
+        Ok(assert_eq!(std::fs::read_to_string(dir.path().join("interrupts"))?, "\n"))
+    }
+
+    #[test]
+    fn test_command_reports_cpu_time_and_memory() -> Result<(), RashError> {
+        let output =
+            Command::new("head -c 20000000 /dev/urandom | sha256sum >/dev/null").output()?;
+        assert!(output.usage.user_time + output.usage.system_time > Duration::ZERO);
+        assert!(output.usage.max_rss > 0);
+        Ok(assert!(output.usage.minor_faults > 0))
+    }
+
+    #[test]
+    fn test_command_reports_when_it_started_and_finished() -> Result<(), RashError> {
+        let before = std::time::SystemTime::now();
+        let output = Command::new("sleep 0.2").output()?;
+        assert!(before <= output.started);
+        assert!(
+            output.finished.duration_since(output.started).unwrap() >= Duration::from_millis(200)
+        );
+        Ok(assert!(output.finished <= std::time::SystemTime::now()))
+    }
+
+    #[test]
+    fn test_command_limit_is_applied() -> Result<(), RashError> {
+        let output = Command::new("ulimit -Sn; ulimit -Hn").limit(Limit::OpenFiles, 64).output()?;
+        Ok(assert_eq!(output.stdout, "64\n64\n"))
+    }
+
+    #[test]
+    fn test_command_cpu_limit_exceeded() {
+        let start = Instant::now();
+        let error = Command::new("while :; do :; done").limit(Limit::Cpu, 1).output().unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(matches!(
+            error,
+            RashError::LimitExceeded {
+                limit: Limit::Cpu,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_command_file_size_limit_exceeded() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let error = Command::new("exec head -c 100000 /dev/zero > big")
+            .current_dir(dir.path())
+            .limit(Limit::FileSize, 1000)
+            .output()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::LimitExceeded {
+                limit: Limit::FileSize,
+                ..
+            }
+        ));
+        Ok(assert_eq!(std::fs::metadata(dir.path().join("big"))?.len(), 1000))
+    }
+
+    #[test]
+    fn test_command_limits_dont_claim_other_failures() -> Result<(), RashError> {
+        let output = Command::new("exit 153").limit(Limit::FileSize, 1000).output()?;
+        assert_eq!(output.ret_val, 128 + libc::SIGXFSZ);
+        let output = Command::new("sh -c 'kill -9 $$'; exit $?").limit(Limit::Cpu, 10).output()?;
+        Ok(assert_eq!(output.ret_val, 128 + libc::SIGKILL))
+    }
+
+    #[test]
+    fn test_command_reports_a_failed_setrlimit() {
+        // Above fs.nr_open, which even root can't go past.
+        let error = Command::new("true").limit(Limit::OpenFiles, 1 << 40).output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Setrlimit,
+                errno: libc::EPERM,
+                ..
+            }
+        ));
+    }
+
+    fn root() -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[test]
+    fn test_command_runs_as_another_user() -> Result<(), RashError> {
+        if !root() {
+            return Ok(());
+        }
+        let command = Command::new("id -u; id -g; id -G").uid(65534).gid(65534).groups([100]);
+        assert_eq!(command.output()?.stdout, "65534\n65534\n65534 100\n");
+        // Without any groups given, root's aren't kept.
+        let output = Command::new("id -u; id -G").uid(65534).output()?;
+        Ok(assert_eq!(output.stdout, "65534\n0\n"))
+    }
+
+    #[test]
+    fn test_command_changes_directory_as_the_new_user() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if !root() {
+            return Ok(());
+        }
+        let dir = tempfile::tempdir()?;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+        let error = Command::new("pwd").uid(65534).current_dir(dir.path()).output().unwrap_err();
+        Ok(assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Chdir,
+                errno: libc::EACCES,
+                ..
+            }
+        )))
+    }
+
+    #[test]
+    fn test_command_chroot() -> anyhow::Result<()> {
+        if !root() {
+            return Ok(());
+        }
+        let output = Command::new("pwd").chroot("/").current_dir("/tmp").output()?;
+        assert_eq!(output.stdout, "/tmp\n");
+
+        // There's no bash to run in an empty root.
+        let dir = tempfile::tempdir()?;
+        let error = Command::new("true").chroot(dir.path()).output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Exec,
+                errno: libc::ENOENT,
+                ..
+            }
+        ));
+        let error = Command::new("true").chroot("/i/do/not/exist").output().unwrap_err();
+        Ok(assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Chroot,
+                errno: libc::ENOENT,
+                ..
+            }
+        )))
+    }
+
+    #[test]
+    fn test_command_umask() -> Result<(), RashError> {
+        let output = Command::new("umask").umask(0o027).output()?;
+        Ok(assert_eq!(output.stdout, "0027\n"))
+    }
+
+    #[test]
+    fn test_command_nice() -> Result<(), RashError> {
+        let output = Command::new("cut -d' ' -f19 /proc/self/stat").nice(19).output()?;
+        Ok(assert_eq!(output.stdout, "19\n"))
+    }
+
+    #[test]
+    fn test_command_cpu_affinity() -> Result<(), RashError> {
+        let command = Command::new("grep Cpus_allowed_list /proc/self/status");
+        let output = command.cpu_affinity([0, 1 << 20]).output()?;
+        Ok(assert_eq!(output.stdout, "Cpus_allowed_list:\t0\n"))
+    }
+
+    #[test]
+    fn test_command_reports_a_failed_affinity() {
+        let error = Command::new("true").cpu_affinity([1 << 20]).output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Affinity,
+                errno: libc::EINVAL,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_child_is_killed_and_reaped_on_drop() -> Result<(), RashError> {
+        // The background job holds stdout open after its parent is killed, so dropping
+        // also mustn't wait for EOF.
+        let child = Command::new("sleep 10 & sleep 100").spawn()?;
+        let (pid, start_time) = (child.pid(), start_time(child.pid()).unwrap());
+        let start = Instant::now();
+        drop(child);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(assert!(reaped(pid, start_time)))
+    }
+
+    #[test]
+    fn test_child_is_killed_and_reaped_on_panic() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let result = std::thread::spawn(move || {
+            let child = Command::new("sleep 100").spawn().unwrap();
+            tx.send((child.pid(), start_time(child.pid()).unwrap())).unwrap();
+            panic!("oh no");
+        })
+        .join();
+        assert!(result.is_err());
+        let (pid, start_time) = rx.recv().unwrap();
+        assert!(reaped(pid, start_time));
+    }
+
+    #[test]
+    fn test_child_is_waited_for_on_drop() -> Result<(), RashError> {
+        let child = Command::new("sleep 0.3").on_drop(OnDrop::Wait).spawn()?;
+        let (pid, start_time) = (child.pid(), start_time(child.pid()).unwrap());
+        let start = Instant::now();
+        drop(child);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        Ok(assert!(reaped(pid, start_time)))
+    }
+
+    #[test]
+    fn test_child_is_detached_on_drop() -> Result<(), RashError> {
+        let child = Command::new("sleep 0.3").on_drop(OnDrop::Detach).spawn()?;
+        let (pid, start_time) = (child.pid(), start_time(child.pid()).unwrap());
+        let start = Instant::now();
+        drop(child);
+        assert!(start.elapsed() < Duration::from_millis(300));
+        assert!(!reaped(pid, start_time));
+
+        std::thread::sleep(Duration::from_secs(1));
+        Ok(assert!(reaped(pid, start_time)))
     }
 }
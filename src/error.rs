@@ -1,29 +1,339 @@
-use libc::{__errno_location, c_int};
-use std::ffi::{CStr, NulError};
+use libc::{c_int, c_long};
+use std::{
+    ffi::{CStr, NulError},
+    fmt, io,
+    time::Duration,
+};
 use thiserror::Error;
 
-use crate::process::ProcessError;
+use crate::{child::Limit, output::TracedCommand, process::ProcessError};
+
+/// A system call which failed, as reported by [`RashError::KernelError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    /// Creating one of the pipes to the child's stdin, stdout or stderr.
+    Pipe,
+    /// Forking the child.
+    Fork,
+    /// Waiting for the child to exit.
+    Waitpid,
+    /// Sending a signal to the child.
+    Kill,
+    /// Opening `/dev/null`, for a child whose stderr is thrown away.
+    Open,
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pipe => "pipe2",
+            Self::Fork => "fork",
+            Self::Waitpid => "waitpid",
+            Self::Kill => "kill",
+            Self::Open => "open",
+        })
+    }
+}
+
+/// The step of setting up the child process, between `fork()` and `exec()`, which failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SpawnStage {
+    /// Wiring the pipes up to the child's stdin, stdout and stderr.
+    Dup,
+    /// Changing into the directory set with [`Command::current_dir`](crate::Command::current_dir).
+    Chdir,
+    /// Exec'ing `/usr/bin/env bash`.
+    Exec,
+    /// Becoming a subreaper, for [`Command::reap_orphans`](crate::Command::reap_orphans).
+    Subreaper,
+    /// Forking the script from the supervisor, for
+    /// [`Command::reap_orphans`](crate::Command::reap_orphans) or a [`Sandbox`](crate::Sandbox).
+    Fork,
+    /// Moving into a new process group, for
+    /// [`Command::forward_signals`](crate::Command::forward_signals).
+    Setpgid,
+    /// Applying a [`Limit`] set with [`Command::limit`](crate::Command::limit).
+    Setrlimit,
+    /// Creating the namespaces for a [`Sandbox`](crate::Sandbox). Failing with `EPERM`,
+    /// `ENOSPC` or `EINVAL` usually means unprivileged user namespaces are disabled, e.g. by
+    /// the `user.max_user_namespaces` or `kernel.unprivileged_userns_clone` sysctls.
+    Unshare,
+    /// Writing the sandbox's `/proc/self/uid_map` or `gid_map`.
+    IdMap,
+    /// Mounting the sandbox's private `/tmp` or `/proc`, or one of its read-only paths.
+    Mount,
+    /// Bringing up the sandbox's loopback interface.
+    Loopback,
+    /// Building or enforcing the [`Landlock`](crate::Landlock) ruleset. Failing with `ENOENT`
+    /// means one of its paths doesn't exist; `ENOSYS` or `EOPNOTSUPP` that the kernel doesn't
+    /// support Landlock, and it wasn't [best effort](crate::Landlock::best_effort).
+    Landlock,
+    /// Setting `PR_SET_NO_NEW_PRIVS` and installing the [`Seccomp`](crate::Seccomp) filter, or
+    /// handing its listener to the supervisor. Failing with `ENOSYS` means filters aren't
+    /// supported on this architecture: only x86_64 and aarch64 are.
+    Seccomp,
+    /// Setting the nice value from [`Command::nice`](crate::Command::nice). Lowering it needs
+    /// `CAP_SYS_NICE`.
+    Nice,
+    /// Pinning the child to the CPUs from
+    /// [`Command::cpu_affinity`](crate::Command::cpu_affinity). Failing with `EINVAL` means
+    /// none of them are available.
+    Affinity,
+    /// Changing root to the directory from [`Command::chroot`](crate::Command::chroot), which
+    /// needs `CAP_SYS_CHROOT`.
+    Chroot,
+    /// Setting the supplementary groups, for [`Command::groups`](crate::Command::groups) or
+    /// when changing user or group. This needs `CAP_SETGID`.
+    Setgroups,
+    /// Changing group to the one from [`Command::gid`](crate::Command::gid).
+    Setgid,
+    /// Changing user to the one from [`Command::uid`](crate::Command::uid).
+    Setuid,
+}
+
+impl SpawnStage {
+    pub(crate) fn from_raw(raw: c_int) -> Option<Self> {
+        [
+            Self::Dup,
+            Self::Chdir,
+            Self::Exec,
+            Self::Subreaper,
+            Self::Fork,
+            Self::Setpgid,
+            Self::Setrlimit,
+            Self::Unshare,
+            Self::IdMap,
+            Self::Mount,
+            Self::Loopback,
+            Self::Landlock,
+            Self::Seccomp,
+            Self::Nice,
+            Self::Affinity,
+            Self::Chroot,
+            Self::Setgroups,
+            Self::Setgid,
+            Self::Setuid,
+        ]
+        .into_iter()
+        .find(|stage| *stage as c_int == raw)
+    }
+}
+
+impl fmt::Display for SpawnStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dup => "dup",
+            Self::Chdir => "chdir",
+            Self::Exec => "exec",
+            Self::Subreaper => "prctl",
+            Self::Fork => "fork",
+            Self::Setpgid => "setpgid",
+            Self::Setrlimit => "setrlimit",
+            Self::Unshare => "unshare",
+            Self::IdMap => "uid_map/gid_map",
+            Self::Mount => "mount",
+            Self::Loopback => "ioctl(SIOCSIFFLAGS)",
+            Self::Landlock => "landlock",
+            Self::Seccomp => "seccomp",
+            Self::Nice => "setpriority",
+            Self::Affinity => "sched_setaffinity",
+            Self::Chroot => "chroot",
+            Self::Setgroups => "setgroups",
+            Self::Setgid => "setgid",
+            Self::Setuid => "setuid",
+        })
+    }
+}
+
+/// Which command an error came from, and how far it got.
+///
+/// Every [`RashError`] carries one of these, and includes it in its `Display` output.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub(crate) command: Option<String>,
+    pub(crate) pid: Option<c_int>,
+    pub(crate) elapsed: Option<Duration>,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    pub(crate) trace: Vec<TracedCommand>,
+}
+
+impl ErrorContext {
+    /// The script which failed, or `"<redacted>"` if it was run with
+    /// [`Command::redact`](crate::Command::redact).
+    pub fn command(&self) -> Option<&str> {
+        self.command.as_deref()
+    }
+
+    /// The pid of the child process, if it got as far as being forked.
+    pub fn pid(&self) -> Option<c_int> {
+        self.pid
+    }
+
+    /// How long the command had been running for when it failed.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.elapsed
+    }
+
+    /// Whatever the command wrote to stdout before it failed.
+    pub fn stdout(&self) -> &str {
+        &self.stdout
+    }
+
+    /// Whatever the command wrote to stderr before it failed.
+    pub fn stderr(&self) -> &str {
+        &self.stderr
+    }
+
+    /// The commands the script ran before it failed, if it was run with
+    /// [`Command::trace`](crate::Command::trace).
+    pub fn trace(&self) -> &[TracedCommand] {
+        &self.trace
+    }
+
+    /// How much of stdout and stderr to show when displaying the error - the tail, as that's
+    /// usually where the reason for the failure is.
+    const DISPLAYED_OUTPUT: usize = 256;
+
+    fn tail(s: &str) -> String {
+        match s.char_indices().rev().nth(Self::DISPLAYED_OUTPUT - 1) {
+            Some((i, _)) if i > 0 => format!("...{}", &s[i..]),
+            _ => s.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(command) = &self.command {
+            parts.push(format!("command: {:?}", command));
+        }
+        if let Some(pid) = self.pid {
+            parts.push(format!("pid: {pid}"));
+        }
+        if let Some(elapsed) = self.elapsed {
+            parts.push(format!("elapsed: {:?}", elapsed));
+        }
+        if !self.stdout.is_empty() {
+            parts.push(format!("stdout: {:?}", Self::tail(&self.stdout)));
+        }
+        if !self.stderr.is_empty() {
+            parts.push(format!("stderr: {:?}", Self::tail(&self.stderr)));
+        }
+        match parts.is_empty() {
+            true => Ok(()),
+            false => write!(f, " [{}]", parts.join(", ")),
+        }
+    }
+}
+
+/// Every variant of [`RashError`] has a `context` field; this gets at it, mutably or not.
+macro_rules! context {
+    ($error:expr) => {
+        match $error {
+            RashError::NullByteInCommand {
+                context,
+                ..
+            }
+            | RashError::KernelError {
+                context,
+                ..
+            }
+            | RashError::FailedToReadStdout {
+                context,
+                ..
+            }
+            | RashError::FailedToReadStderr {
+                context,
+                ..
+            }
+            | RashError::SpawnFailed {
+                context,
+                ..
+            }
+            | RashError::CommandNotFound {
+                context,
+                ..
+            }
+            | RashError::CommandNotExecutable {
+                context,
+                ..
+            }
+            | RashError::KilledBySignal {
+                context,
+                ..
+            }
+            | RashError::Interrupted {
+                context,
+                ..
+            }
+            | RashError::LimitExceeded {
+                context,
+                ..
+            }
+            | RashError::SyscallBlocked {
+                context,
+                ..
+            }
+            | RashError::SessionDied {
+                context,
+                ..
+            }
+            | RashError::StageFailed {
+                context,
+                ..
+            }
+            | RashError::ScriptFailed {
+                context,
+                ..
+            }
+            | RashError::TimedOut {
+                context,
+                ..
+            }
+            | RashError::InvalidOption {
+                context,
+                ..
+            } => context,
+        }
+    };
+}
 
 /// The error thrown if something went wrong in the processing of the command.
+///
+/// Two errors are equal if they're the same variant with the same details, whatever their
+/// [`ErrorContext`]s. A [`io::Error`] source is compared by its kind alone, `KernelError`s
+/// already being compared by errno.
 #[cfg(unix)]
-#[derive(Error, Debug, PartialEq)]
+#[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum RashError {
     /// The given command contained a null byte.
     /// Commands must **not** contain null bytes as they're converted into CStrings.
     ///
     /// If this error is thrown, the error message will contain the position
     /// of the null byte in the command.
-    #[error("Null byte found in command at pos {}", pos)]
+    ///
+    /// Every variant also carries an [`ErrorContext`], describing the command which failed.
+    #[error("Null byte found in command at pos {}{}", pos, context)]
     NullByteInCommand {
         pos: usize,
+        context: Box<ErrorContext>,
     },
     /// A system call failed.
     ///
-    /// If this error is thrown, the error message will contain the errno,
-    /// a description of syscall that failed, and the strerror output.
-    #[error("{:?}", message)]
+    /// If this error is thrown, `syscall` is the system call which failed and `errno` is the
+    /// errno it set, captured straight after the call. The error's `source()` is the
+    /// equivalent [`std::io::Error`].
+    #[error("{syscall} failed with errno {errno}: {source}{context}")]
     KernelError {
-        message: String,
+        syscall: Syscall,
+        errno: c_int,
+        #[source]
+        source: io::Error,
+        context: Box<ErrorContext>,
     },
     /// We couldn't obtain stdout.
     /// This can occur if the stdout is not valid UTF-8
@@ -31,9 +341,10 @@ pub enum RashError {
     ///
     /// If this error is thrown, the error message will be the error message
     /// given by calling `to_string()` on the source error.
-    #[error("Couldn't read stdout: {:?}", message)]
+    #[error("Couldn't read stdout: {:?}{}", message, context)]
     FailedToReadStdout {
         message: String,
+        context: Box<ErrorContext>,
     },
     /// We couldn't obtain stderr.
     /// This can occur if the stderr is not valid UTF-8
@@ -41,29 +352,372 @@ pub enum RashError {
     ///
     /// If this error is thrown, the error message will be the error message
     /// given by calling `to_string()` on the source error.
-    #[error("Couldn't read stderr: {:?}", message)]
+    #[error("Couldn't read stderr: {:?}{}", message, context)]
     FailedToReadStderr {
         message: String,
+        context: Box<ErrorContext>,
+    },
+    /// The child process failed before it could start running bash.
+    ///
+    /// If this error is thrown, `stage` is the step which failed, `errno` is the errno it
+    /// failed with, and `message` is the strerror output for that errno.
+    #[error(
+        "Couldn't spawn the command - {stage} failed with errno {errno}: {:?}{}",
+        message,
+        context
+    )]
+    SpawnFailed {
+        stage: SpawnStage,
+        errno: c_int,
+        message: String,
+        context: Box<ErrorContext>,
+    },
+    /// bash exited with 127, i.e. the command couldn't be found.
+    ///
+    /// If this error is thrown, the message will be the command's stderr.
+    #[error("Command not found: {:?}{}", message, context)]
+    CommandNotFound {
+        message: String,
+        context: Box<ErrorContext>,
     },
+    /// bash exited with 126, i.e. the command was found but couldn't be executed.
+    ///
+    /// If this error is thrown, the message will be the command's stderr.
+    #[error("Command not executable: {:?}{}", message, context)]
+    CommandNotExecutable {
+        message: String,
+        context: Box<ErrorContext>,
+    },
+    /// The command was killed by a signal, rather than exiting.
+    ///
+    /// If this error is thrown, `signal` is the number of the signal which killed it.
+    #[error("Command was killed by signal {signal}{context}")]
+    KilledBySignal {
+        signal: c_int,
+        context: Box<ErrorContext>,
+    },
+    /// The host received a signal while the command was running, and relayed it to the
+    /// command, as set up by [`Command::forward_signals`](crate::Command::forward_signals).
+    ///
+    /// If this error is thrown, `signal` is the last signal relayed, and the context carries
+    /// whatever output the command produced before it stopped.
+    #[error("Command was interrupted by signal {signal}{context}")]
+    Interrupted {
+        signal: c_int,
+        context: Box<ErrorContext>,
+    },
+    /// The command was stopped for going over a [`Limit`] set with
+    /// [`Command::limit`](crate::Command::limit).
+    ///
+    /// This covers bash itself being killed by `SIGXCPU` or `SIGXFSZ`, and bash or its last
+    /// command being killed by `SIGXCPU` or `SIGKILL` once the command has used up its CPU
+    /// time. The other limits make system calls fail instead, which shows up in the command's
+    /// own output.
+    #[error("Command exceeded its {limit} limit{context}")]
+    LimitExceeded {
+        limit: Limit,
+        context: Box<ErrorContext>,
+    },
+    /// A process in the command made a system call denied by its
+    /// [`Seccomp`](crate::Seccomp) filter, and was killed by `SIGSYS`.
+    ///
+    /// If this error is thrown, `syscall` is the number of the call (as in `libc::SYS_*`), and
+    /// `pid` and `name` identify the process which made it. Only the first is reported.
+    #[error(
+        "Command was killed by signal {} for making blocked system call {syscall} \
+         (pid {pid}, {name:?}){context}",
+        libc::SIGSYS
+    )]
+    SyscallBlocked {
+        syscall: c_long,
+        pid: c_int,
+        name: String,
+        context: Box<ErrorContext>,
+    },
+    /// The shell behind a [`Session`](crate::Session) exited while running the command, e.g.
+    /// because the command called `exit`, or the shell was killed. The session starts a new
+    /// shell for its next command, without any of the old one's state.
+    ///
+    /// If this error is thrown, `ret_val` is the shell's exit code, or 128 plus the signal
+    /// which killed it.
+    #[error("Session's shell exited with {ret_val} while running the command{context}")]
+    SessionDied {
+        ret_val: c_int,
+        context: Box<ErrorContext>,
+    },
+    /// A Rust stage of a [`Pipeline`](crate::Pipeline) returned an error, or panicked.
+    ///
+    /// If this error is thrown, `stage` is the stage's index in the pipeline, counting from 0,
+    /// and `message` is the error, or what the stage panicked with. The error's `source()` is
+    /// the error the stage returned, if it didn't panic.
+    #[error("Pipeline stage {stage} failed: {message:?}{context}")]
+    StageFailed {
+        stage: usize,
+        message: String,
+        #[source]
+        source: Option<io::Error>,
+        context: Box<ErrorContext>,
+    },
+    /// A command in a script run in [strict mode](crate::Command::strict) failed, and took the
+    /// script down with it.
+    ///
+    /// If this error is thrown, `ret_val` is the script's exit code, `line` is the line of the
+    /// script the failing command was on, counting from 1, and `command` is the command as bash
+    /// saw it, e.g. `grep -q foo file`.
+    #[error("Script failed with {ret_val} at line {line} running {command:?}{context}")]
+    ScriptFailed {
+        ret_val: c_int,
+        line: u32,
+        command: String,
+        context: Box<ErrorContext>,
+    },
+    /// The command was still running when its time ran out, as set with
+    /// [`Session::set_timeout`](crate::Session::set_timeout), and was killed.
+    ///
+    /// If this error is thrown, `timeout` is how long it was given.
+    #[error("Command timed out after {timeout:?}{context}")]
+    TimedOut {
+        timeout: Duration,
+        context: Box<ErrorContext>,
+    },
+    /// Something was asked for which can't be done, e.g. a
+    /// [`SessionPool`](crate::SessionPool) with no sessions in it, or a
+    /// [`Pipeline`](crate::Pipeline) stage in strict mode. Nothing was run.
+    ///
+    /// If this error is thrown, `message` says what was wrong.
+    #[error("Invalid option: {message}{context}")]
+    InvalidOption {
+        message: String,
+        context: Box<ErrorContext>,
+    },
+}
+
+impl PartialEq for RashError {
+    fn eq(&self, other: &Self) -> bool {
+        use RashError::*;
+        match (self, other) {
+            (
+                NullByteInCommand {
+                    pos,
+                    ..
+                },
+                NullByteInCommand {
+                    pos: other,
+                    ..
+                },
+            ) => pos == other,
+            (
+                KernelError {
+                    syscall,
+                    errno,
+                    ..
+                },
+                KernelError {
+                    syscall: other_syscall,
+                    errno: other_errno,
+                    ..
+                },
+            ) => (syscall, errno) == (other_syscall, other_errno),
+            (
+                FailedToReadStdout {
+                    message,
+                    ..
+                },
+                FailedToReadStdout {
+                    message: other,
+                    ..
+                },
+            )
+            | (
+                FailedToReadStderr {
+                    message,
+                    ..
+                },
+                FailedToReadStderr {
+                    message: other,
+                    ..
+                },
+            )
+            | (
+                CommandNotFound {
+                    message,
+                    ..
+                },
+                CommandNotFound {
+                    message: other,
+                    ..
+                },
+            )
+            | (
+                CommandNotExecutable {
+                    message,
+                    ..
+                },
+                CommandNotExecutable {
+                    message: other,
+                    ..
+                },
+            ) => message == other,
+            (
+                SpawnFailed {
+                    stage,
+                    errno,
+                    ..
+                },
+                SpawnFailed {
+                    stage: other_stage,
+                    errno: other_errno,
+                    ..
+                },
+            ) => (stage, errno) == (other_stage, other_errno),
+            (
+                KilledBySignal {
+                    signal,
+                    ..
+                },
+                KilledBySignal {
+                    signal: other,
+                    ..
+                },
+            )
+            | (
+                Interrupted {
+                    signal,
+                    ..
+                },
+                Interrupted {
+                    signal: other,
+                    ..
+                },
+            ) => signal == other,
+            (
+                SessionDied {
+                    ret_val,
+                    ..
+                },
+                SessionDied {
+                    ret_val: other,
+                    ..
+                },
+            ) => ret_val == other,
+            (
+                LimitExceeded {
+                    limit,
+                    ..
+                },
+                LimitExceeded {
+                    limit: other,
+                    ..
+                },
+            ) => limit == other,
+            (
+                SyscallBlocked {
+                    syscall,
+                    pid,
+                    name,
+                    ..
+                },
+                SyscallBlocked {
+                    syscall: other_syscall,
+                    pid: other_pid,
+                    name: other_name,
+                    ..
+                },
+            ) => (syscall, pid, name) == (other_syscall, other_pid, other_name),
+            (
+                StageFailed {
+                    stage,
+                    message,
+                    source,
+                    ..
+                },
+                StageFailed {
+                    stage: other_stage,
+                    message: other_message,
+                    source: other_source,
+                    ..
+                },
+            ) => {
+                (stage, message) == (other_stage, other_message)
+                    && source.as_ref().map(io::Error::kind)
+                        == other_source.as_ref().map(io::Error::kind)
+            }
+            (
+                ScriptFailed {
+                    ret_val,
+                    line,
+                    command,
+                    ..
+                },
+                ScriptFailed {
+                    ret_val: other_ret_val,
+                    line: other_line,
+                    command: other_command,
+                    ..
+                },
+            ) => (ret_val, line, command) == (other_ret_val, other_line, other_command),
+            (
+                TimedOut {
+                    timeout,
+                    ..
+                },
+                TimedOut {
+                    timeout: other,
+                    ..
+                },
+            ) => timeout == other,
+            (
+                InvalidOption {
+                    message,
+                    ..
+                },
+                InvalidOption {
+                    message: other,
+                    ..
+                },
+            ) => message == other,
+            _ => false,
+        }
+    }
 }
 
 impl From<ProcessError> for RashError {
     fn from(v: ProcessError) -> Self {
-        fn into_kernel_error<S: AsRef<str>>(s: S) -> RashError {
+        fn into_kernel_error(syscall: Syscall, errno: c_int) -> RashError {
             RashError::KernelError {
-                message: unsafe { RashError::format_kernel_error_message(s) },
+                syscall,
+                errno,
+                source: io::Error::from_raw_os_error(errno),
+                context: Box::default(),
             }
         }
         match v {
-            ProcessError::CouldNotFork
-            | ProcessError::CouldNotCreatePipe
-            | ProcessError::CouldNotDupFd(_)
-            | ProcessError::OpenDidNotCloseNormally => into_kernel_error(v.to_string()),
+            ProcessError::CouldNotCreatePipe(errno) => into_kernel_error(Syscall::Pipe, errno),
+            ProcessError::CouldNotFork(errno) => into_kernel_error(Syscall::Fork, errno),
+            ProcessError::CouldNotWait(errno) => into_kernel_error(Syscall::Waitpid, errno),
+            ProcessError::CouldNotKill(errno) => into_kernel_error(Syscall::Kill, errno),
+            ProcessError::CouldNotOpenNull(errno) => into_kernel_error(Syscall::Open, errno),
+            ProcessError::OpenDidNotCloseNormally(signal) => RashError::KilledBySignal {
+                signal,
+                context: Box::default(),
+            },
+            ProcessError::Interrupted(signal) => RashError::Interrupted {
+                signal,
+                context: Box::default(),
+            },
             ProcessError::CouldNotGetStderr => RashError::FailedToReadStderr {
                 message: v.to_string(),
+                context: Box::default(),
             },
             ProcessError::CouldNotGetStdout => RashError::FailedToReadStdout {
                 message: v.to_string(),
+                context: Box::default(),
+            },
+            ProcessError::SpawnFailed(stage, errno) => RashError::SpawnFailed {
+                stage,
+                errno,
+                message: unsafe { RashError::strerror(errno) },
+                context: Box::default(),
             },
         }
     }
@@ -73,19 +727,67 @@ impl From<NulError> for RashError {
     fn from(v: NulError) -> Self {
         RashError::NullByteInCommand {
             pos: v.nul_position(),
+            context: Box::default(),
         }
     }
 }
 
 impl RashError {
-    pub(crate) unsafe fn format_kernel_error_message<S: AsRef<str>>(description: S) -> String {
-        let errno = *__errno_location();
-        let strerror = Self::strerror(errno);
-        format!(
-            "Received errno {}, Description: {}, strerror output: {strerror}.",
-            errno.to_string(),
-            description.as_ref()
-        )
+    /// Details of the command which failed.
+    pub fn context(&self) -> &ErrorContext {
+        context!(self)
+    }
+
+    /// The script which failed, or `"<redacted>"` if it was run with
+    /// [`Command::redact`](crate::Command::redact).
+    pub fn command(&self) -> Option<&str> {
+        self.context().command()
+    }
+
+    /// The pid of the child process, if it got as far as being forked.
+    pub fn pid(&self) -> Option<c_int> {
+        self.context().pid()
+    }
+
+    /// How long the command had been running for when it failed.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.context().elapsed()
+    }
+
+    /// Whatever the command wrote to stdout before it failed.
+    pub fn stdout(&self) -> &str {
+        self.context().stdout()
+    }
+
+    /// Whatever the command wrote to stderr before it failed.
+    pub fn stderr(&self) -> &str {
+        self.context().stderr()
+    }
+
+    /// The commands the script ran before it failed, if it was run with
+    /// [`Command::trace`](crate::Command::trace).
+    pub fn trace(&self) -> &[TracedCommand] {
+        self.context().trace()
+    }
+
+    pub(crate) fn with_context(mut self, context: ErrorContext) -> Self {
+        **context!(&mut self) = context;
+        self
+    }
+
+    /// The errno behind this error, if it came from a failed system call.
+    pub fn errno(&self) -> Option<c_int> {
+        match self {
+            Self::KernelError {
+                errno,
+                ..
+            }
+            | Self::SpawnFailed {
+                errno,
+                ..
+            } => Some(*errno),
+            _ => None,
+        }
     }
 
     unsafe fn strerror(errno: c_int) -> String {
@@ -99,3 +801,74 @@ impl RashError {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::{RashError, SpawnStage, Syscall};
+    use crate::process::ProcessError;
+
+    #[test]
+    fn test_errors_are_equal_whatever_their_context() {
+        let error = |errno| RashError::from(ProcessError::CouldNotFork(errno));
+        let mut context = error(libc::EAGAIN);
+        if let RashError::KernelError {
+            context,
+            ..
+        } = &mut context
+        {
+            context.pid = Some(1);
+        }
+        assert_eq!(error(libc::EAGAIN), context);
+        assert_ne!(error(libc::EAGAIN), error(libc::ENOMEM));
+        assert_ne!(
+            error(libc::EAGAIN),
+            RashError::from(ProcessError::CouldNotCreatePipe(libc::EAGAIN))
+        );
+        assert_ne!(error(libc::EAGAIN), RashError::from(ProcessError::CouldNotGetStdout));
+    }
+
+    #[test]
+    fn test_error_context_can_be_read() {
+        let error = crate::Command::new("echo out; echo err >&2; exit 127").output().unwrap_err();
+        let context = error.context();
+        assert_eq!(context.command(), Some("echo out; echo err >&2; exit 127"));
+        assert!(context.pid().is_some() && context.elapsed().is_some());
+        assert_eq!((context.stdout(), context.stderr()), ("out\n", "err\n"));
+        assert!(context.trace().is_empty());
+    }
+
+    #[test]
+    fn test_kernel_error_keeps_the_errno_it_was_given() {
+        let error = RashError::from(ProcessError::CouldNotFork(libc::EAGAIN));
+        assert!(matches!(
+            error,
+            RashError::KernelError {
+                syscall: Syscall::Fork,
+                errno: libc::EAGAIN,
+                ..
+            }
+        ));
+        assert_eq!(error.errno(), Some(libc::EAGAIN));
+    }
+
+    #[test]
+    fn test_spawn_stage_from_raw_knows_every_stage() {
+        for raw in 0..SpawnStage::Setuid as libc::c_int {
+            assert_eq!(SpawnStage::from_raw(raw).map(|stage| stage as libc::c_int), Some(raw));
+        }
+        assert_eq!(
+            SpawnStage::from_raw(SpawnStage::Setuid as libc::c_int),
+            Some(SpawnStage::Setuid)
+        );
+    }
+
+    #[test]
+    fn test_kernel_error_source_is_an_io_error() {
+        let error = RashError::from(ProcessError::CouldNotCreatePipe(libc::EMFILE));
+        let source = error.source().unwrap().downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.raw_os_error(), Some(libc::EMFILE));
+        assert!(error.to_string().starts_with("pipe2 failed with errno 24: "));
+    }
+}
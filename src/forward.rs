@@ -0,0 +1,127 @@
+use libc::{
+    __errno_location, c_int, kill, sigaction, sigemptyset, SA_RESTART, SIGHUP, SIGINT, SIGTERM,
+};
+use std::{
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Mutex,
+    },
+};
+
+/// The signals relayed to commands run with
+/// [`Command::forward_signals`](crate::Command::forward_signals).
+pub(crate) const FORWARDED_SIGNALS: [c_int; 3] = [SIGINT, SIGTERM, SIGHUP];
+
+/// How many forwarding commands can be running at once.
+const SLOTS: usize = 1024;
+
+/// One running command's process group, and the last signal relayed to it.
+///
+/// The signal handler can't take locks or allocate, so the registry is a fixed array of these
+/// rather than anything growable.
+struct Slot {
+    taken: AtomicBool,
+    pgid: AtomicI32,
+    signal: AtomicI32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: Slot = Slot {
+    taken: AtomicBool::new(false),
+    pgid: AtomicI32::new(0),
+    signal: AtomicI32::new(0),
+};
+
+static REGISTRY: [Slot; SLOTS] = [FREE; SLOTS];
+
+/// How many slots are taken, and the handlers ours replaced, to be put back once none are.
+static INSTALLED: Mutex<(usize, Vec<(c_int, sigaction)>)> = Mutex::new((0, Vec::new()));
+
+extern "C" fn relay(signal: c_int) {
+    unsafe {
+        let errno = *__errno_location();
+        for slot in REGISTRY.iter().filter(|slot| slot.taken.load(Ordering::SeqCst)) {
+            slot.signal.store(signal, Ordering::SeqCst);
+            let pgid = slot.pgid.load(Ordering::SeqCst);
+            if pgid > 0 {
+                kill(-pgid, signal);
+            }
+        }
+        *__errno_location() = errno;
+    }
+}
+
+/// A command's registration for having the host's SIGINT, SIGTERM and SIGHUP relayed to its
+/// process group.
+///
+/// The host's own handlers are swapped out while at least one registration is alive, and put
+/// back when the last one is dropped, so outside of such commands Ctrl-C behaves as usual.
+pub(crate) struct Forwarding {
+    slot: &'static Slot,
+}
+
+impl Forwarding {
+    /// Take a slot, before forking, so that a signal which arrives while the child is still
+    /// being set up isn't lost. Returns `None` if every slot is taken.
+    pub(crate) fn register() -> Option<Self> {
+        let slot = REGISTRY.iter().find(|slot| {
+            slot.taken.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        })?;
+        slot.pgid.store(0, Ordering::SeqCst);
+        slot.signal.store(0, Ordering::SeqCst);
+
+        let mut installed = INSTALLED.lock().unwrap_or_else(|e| e.into_inner());
+        if installed.0 == 0 {
+            installed.1 = FORWARDED_SIGNALS
+                .iter()
+                .map(|signal| unsafe {
+                    let mut action = MaybeUninit::<sigaction>::zeroed().assume_init();
+                    let mut previous = MaybeUninit::<sigaction>::zeroed().assume_init();
+                    action.sa_sigaction = relay as extern "C" fn(c_int) as usize;
+                    action.sa_flags = SA_RESTART;
+                    sigemptyset(&mut action.sa_mask);
+                    sigaction(*signal, &action, &mut previous);
+                    (*signal, previous)
+                })
+                .collect();
+        }
+        installed.0 += 1;
+        Some(Self {
+            slot,
+        })
+    }
+
+    /// Start relaying to `pgid`, passing on anything which arrived before we knew it.
+    pub(crate) fn attach(&self, pgid: c_int) {
+        self.slot.pgid.store(pgid, Ordering::SeqCst);
+        match self.slot.signal.load(Ordering::SeqCst) {
+            0 => {}
+            signal => unsafe {
+                kill(-pgid, signal);
+            },
+        }
+    }
+
+    /// The last signal relayed to the command, if any was.
+    pub(crate) fn interrupted(&self) -> Option<c_int> {
+        match self.slot.signal.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+}
+
+impl Drop for Forwarding {
+    fn drop(&mut self) {
+        let mut installed = INSTALLED.lock().unwrap_or_else(|e| e.into_inner());
+        installed.0 -= 1;
+        if installed.0 == 0 {
+            for (signal, previous) in installed.1.drain(..) {
+                unsafe { sigaction(signal, &previous, std::ptr::null_mut()) };
+            }
+        }
+        self.slot.pgid.store(0, Ordering::SeqCst);
+        self.slot.taken.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,322 @@
+use libc::{
+    c_int, c_void, close, open, prctl, syscall, SYS_landlock_add_rule, SYS_landlock_create_ruleset,
+    SYS_landlock_restrict_self, O_CLOEXEC, O_PATH, PR_SET_NO_NEW_PRIVS,
+};
+use std::{
+    ffi::CString,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::error::SpawnStage;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Everything from `LANDLOCK_ACCESS_FS_REMOVE_DIR` up to `LANDLOCK_ACCESS_FS_MAKE_SYM`.
+const ACCESS_FS_MODIFY_DIR: u64 = 0x1ff0;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+/// The rights which make sense on a file, rather than a directory.
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: c_int,
+}
+
+/// Filesystem restrictions for a [`Command`](crate::Command), enforced by the kernel with
+/// Landlock (see landlock(7)), set with [`Command::landlock`](crate::Command::landlock).
+///
+/// The command can only read and execute beneath the [read-only](Landlock::read_only) paths,
+/// and only write beneath the [read-write](Landlock::read_write) ones. Nothing else on the
+/// filesystem can be touched at all - so bash's own binaries and libraries need allowing too.
+/// Unlike a [`Sandbox`](crate::Sandbox), this needs no namespaces, and works unprivileged.
+///
+/// Each kernel supports a particular version of Landlock's ABI, with later versions able to
+/// restrict more kinds of access. The rules are cut down to what the running kernel
+/// supports, and the version enforced is reported in
+/// [`Output::landlock_abi`](crate::Output::landlock_abi).
+///
+/// ```
+/// use rsbash::{Command, Landlock, RashError};
+///
+/// pub fn restricted() -> Result<(), RashError> {
+///     let dir = tempfile::tempdir().unwrap();
+///     let landlock = Landlock::new().read_only("/usr").read_only("/etc");
+///     let output = Command::new("echo hi > \"$1\"")
+///         .arg(dir.path().join("file"))
+///         .landlock(landlock)
+///         .output()?;
+///     if output.landlock_abi != Some(0) {
+///         assert!(output.stderr.contains("Permission denied"));
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Landlock {
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+    best_effort: bool,
+}
+
+impl Default for Landlock {
+    fn default() -> Self {
+        Self {
+            read_only: Vec::new(),
+            read_write: Vec::new(),
+            best_effort: true,
+        }
+    }
+}
+
+impl Landlock {
+    /// Restrictions which allow access to nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow reading and executing anything beneath `path`.
+    pub fn read_only<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_only.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Allow any access to anything beneath `path`.
+    pub fn read_write<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_write.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Whether to run the command unrestricted, rather than fail, if the kernel doesn't
+    /// support Landlock at all. Defaults to `true`.
+    ///
+    /// When `false`, the command fails to spawn with [`SpawnStage::Landlock`].
+    pub fn best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+}
+
+/// A Landlock ruleset, built in the parent, for the forked child to restrict itself with.
+pub(crate) struct LandlockPlan {
+    ruleset: Option<OwnedFd>,
+    abi: u32,
+    /// Why the ruleset couldn't be built, for the child to report as if it had failed itself.
+    errno: Option<c_int>,
+}
+
+impl LandlockPlan {
+    pub(crate) fn new(landlock: &Landlock) -> Self {
+        let abi = unsafe {
+            syscall(
+                SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Self {
+                ruleset: None,
+                abi: 0,
+                errno: (!landlock.best_effort).then(errno),
+            };
+        }
+        let abi = abi as u32;
+        match unsafe { Self::ruleset(landlock, Self::handled(abi)) } {
+            Ok(ruleset) => Self {
+                ruleset: Some(ruleset),
+                abi,
+                errno: None,
+            },
+            Err(errno) => Self {
+                ruleset: None,
+                abi,
+                errno: Some(errno),
+            },
+        }
+    }
+
+    /// The Landlock ABI version which will be enforced, or 0 if none will be.
+    pub(crate) fn abi(&self) -> u32 {
+        match self.ruleset {
+            Some(_) => self.abi,
+            None => 0,
+        }
+    }
+
+    /// The ruleset's fd, which the child has to keep open until it has called
+    /// [`restrict`](LandlockPlan::restrict).
+    pub(crate) fn fd(&self) -> c_int {
+        self.ruleset.as_ref().map_or(-1, |fd| fd.as_raw_fd())
+    }
+
+    /// Every access right the given ABI version knows about.
+    fn handled(abi: u32) -> u64 {
+        let mut handled = ACCESS_READ | ACCESS_FS_WRITE_FILE | ACCESS_FS_MODIFY_DIR;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+        if abi >= 5 {
+            handled |= ACCESS_FS_IOCTL_DEV;
+        }
+        handled
+    }
+
+    unsafe fn ruleset(landlock: &Landlock, handled: u64) -> Result<OwnedFd, c_int> {
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let ruleset = match syscall(SYS_landlock_create_ruleset, &attr, size_of::<RulesetAttr>(), 0)
+        {
+            -1 => return Err(errno()),
+            fd => OwnedFd::from_raw_fd(fd as c_int),
+        };
+        let rules = landlock
+            .read_only
+            .iter()
+            .map(|path| (path, ACCESS_READ & handled))
+            .chain(landlock.read_write.iter().map(|path| (path, handled)));
+        for (path, access) in rules {
+            let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| libc::EINVAL)?;
+            let fd = match open(path.as_ptr(), O_PATH | O_CLOEXEC) {
+                -1 => return Err(errno()),
+                fd => OwnedFd::from_raw_fd(fd),
+            };
+            let mut stat = std::mem::MaybeUninit::<libc::stat>::zeroed().assume_init();
+            if libc::fstat(fd.as_raw_fd(), &mut stat) == -1 {
+                return Err(errno());
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: match stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                    true => access,
+                    false => access & ACCESS_FILE,
+                },
+                parent_fd: fd.as_raw_fd(),
+            };
+            let rule = &rule as *const PathBeneathAttr as *const c_void;
+            let fd = ruleset.as_raw_fd();
+            if syscall(SYS_landlock_add_rule, fd, LANDLOCK_RULE_PATH_BENEATH, rule, 0) == -1 {
+                return Err(errno());
+            }
+        }
+        Ok(ruleset)
+    }
+
+    /// Restrict the calling process, and everything it goes on to run, to the ruleset, which
+    /// has been kept open at `fd`. Only ever call this in the child, straight after `fork()`.
+    ///
+    /// On failure, returns the stage which failed, with errno left as it set it.
+    pub(crate) unsafe fn restrict(&self, fd: c_int) -> Result<(), SpawnStage> {
+        if let Some(errno) = self.errno {
+            *libc::__errno_location() = errno;
+            return Err(SpawnStage::Landlock);
+        }
+        if fd == -1 {
+            return Ok(());
+        }
+        // Required of unprivileged processes, so they can't exec a setuid binary to escape.
+        if prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1
+            || syscall(SYS_landlock_restrict_self, fd, 0) == -1
+        {
+            return Err(SpawnStage::Landlock);
+        }
+        close(fd);
+        Ok(())
+    }
+}
+
+fn errno() -> c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Landlock, LandlockPlan};
+    use crate::{Command, RashError, SpawnStage};
+
+    fn system() -> Landlock {
+        ["/usr", "/bin", "/lib", "/lib64", "/etc"]
+            .into_iter()
+            .filter(|path| std::path::Path::new(path).exists())
+            .fold(Landlock::new(), |landlock, path| landlock.read_only(path))
+    }
+
+    #[test]
+    fn test_landlock_handled_rights_grow_with_the_abi() {
+        assert_eq!(LandlockPlan::handled(1), 0x1fff);
+        assert_eq!(LandlockPlan::handled(2), 0x3fff);
+        assert_eq!(LandlockPlan::handled(4), 0x7fff);
+        assert_eq!(LandlockPlan::handled(7), 0xffff);
+    }
+
+    #[test]
+    fn test_landlock_restricts_the_filesystem() -> anyhow::Result<()> {
+        let read_only = tempfile::tempdir()?;
+        let read_write = tempfile::tempdir()?;
+        let hidden = tempfile::tempdir()?;
+        std::fs::write(read_only.path().join("file"), "read only\n")?;
+        std::fs::write(hidden.path().join("file"), "hidden\n")?;
+
+        let command = format!(
+            "cat {ro}/file; echo written > {rw}/file; cat {hidden}/file; touch {ro}/new",
+            ro = read_only.path().display(),
+            rw = read_write.path().display(),
+            hidden = hidden.path().display(),
+        );
+        let landlock = system().read_only(read_only.path()).read_write(read_write.path());
+        let output = match Command::new(command).landlock(landlock).output() {
+            Err(RashError::SpawnFailed {
+                stage: SpawnStage::Landlock,
+                ..
+            }) => return Ok(()), // No Landlock in this kernel.
+            output => output?,
+        };
+        if output.landlock_abi == Some(0) {
+            return Ok(());
+        }
+        assert_eq!(output.stdout, "read only\n");
+        assert_eq!(std::fs::read_to_string(read_write.path().join("file"))?, "written\n");
+        assert_eq!(output.stderr.matches("Permission denied").count(), 2, "{}", output.stderr);
+        Ok(assert!(!read_only.path().join("new").exists()))
+    }
+
+    #[test]
+    fn test_landlock_reports_a_missing_path() {
+        let landlock = system().read_only("/i/do/not/exist").best_effort(false);
+        let error = Command::new("true").landlock(landlock).output().unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Landlock,
+                ..
+            }
+        ));
+    }
+}
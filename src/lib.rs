@@ -53,14 +53,42 @@
 //! ```
 //!
 //! See the [`rash!`](macro@rash) and [`rashf!`](macro@rashf) macros, and the [`RashError`](enum@RashError) for more information.
+//! For more control over how a command is run, see [`Command`](struct@Command).
 #[macro_use]
 extern crate lazy_static;
 
-pub use crate::error::RashError;
+#[cfg(feature = "rayon")]
+pub use crate::batch::par_run_all;
+pub use crate::{
+    batch::{map, run_all, Batch, BatchOutput, BatchSummary, Progress},
+    child::Limit,
+    command::{Child, Command},
+    error::{ErrorContext, RashError, SpawnStage, Syscall},
+    landlock::Landlock,
+    output::{Output, PipelineOutput, Straggler, TracedCommand, Usage},
+    pipeline::Pipeline,
+    pool::{PoolMetrics, PooledSession, SessionPool},
+    process::{OnDrop, Stderr},
+    retry::{Attempt, Retried, Retry},
+    sandbox::Sandbox,
+    seccomp::Seccomp,
+    session::Session,
+};
 
+mod batch;
+mod child;
 mod command;
 mod error;
+mod forward;
+mod landlock;
+mod output;
+mod pipeline;
+mod pool;
 mod process;
+mod retry;
+mod sandbox;
+mod seccomp;
+mod session;
 #[doc(hidden)]
 pub mod shell;
 
@@ -76,6 +104,26 @@ pub mod shell;
 ///
 /// See [`RashError`](enum@RashError) for more details of the error.
 ///
+/// #### Errors:
+/// A non-zero return value is still an `Ok`, with three exceptions:
+/// * bash returning 127, meaning a command couldn't be found, is a [`RashError::CommandNotFound`](enum@RashError).
+/// * bash returning 126, meaning a command was found but couldn't be executed, is a [`RashError::CommandNotExecutable`](enum@RashError).
+/// * bash itself being killed by a signal is a [`RashError::KilledBySignal`](enum@RashError).
+///   A command *within* the script being killed is an `Ok` with bash's usual 128 + the signal.
+///
+/// In each case, the error carries whatever the command wrote to stdout and stderr.
+///
+/// ```
+/// use rsbash::{rash, RashError};
+///
+/// pub fn not_found() {
+///     match rash!("i_am_not_a_valid_executable") {
+///         Err(e @ RashError::CommandNotFound { .. }) => assert!(e.stderr().contains("command not found")),
+///         other => panic!("{other:?}"),
+///     }
+/// }
+/// ```
+///
 /// # Examples
 ///#### A simple command:
 ///```
@@ -176,6 +224,26 @@ macro_rules! rash {
 ///
 /// See [`RashError`](enum@RashError) for more details of the error.
 ///
+/// #### Errors:
+/// A non-zero return value is still an `Ok`, with three exceptions:
+/// * bash returning 127, meaning a command couldn't be found, is a [`RashError::CommandNotFound`](enum@RashError).
+/// * bash returning 126, meaning a command was found but couldn't be executed, is a [`RashError::CommandNotExecutable`](enum@RashError).
+/// * bash itself being killed by a signal is a [`RashError::KilledBySignal`](enum@RashError).
+///   A command *within* the script being killed is an `Ok` with bash's usual 128 + the signal.
+///
+/// In each case, the error carries whatever the command wrote to stdout and stderr.
+///
+/// ```
+/// use rsbash::{rashf, RashError};
+///
+/// pub fn not_found() {
+///     match rashf!("i_am_not_a_valid_executable") {
+///         Err(e @ RashError::CommandNotFound { .. }) => assert!(e.stderr().contains("command not found")),
+///         other => panic!("{other:?}"),
+///     }
+/// }
+/// ```
+///
 /// # Examples
 ///
 /// #### Formatting:
@@ -266,6 +334,44 @@ macro_rules! rashf {
     };
 }
 
+/// Run a bash command in strict mode, stopping at the first command to fail.
+///
+/// #### Arguments:
+/// `rash_strict!` takes the same arguments as [`rash!`](macro@rash): a single String or string literal.
+///
+/// #### Returns:
+/// `rash_strict!` returns a `Result<(i32, String, String), RashError>`, just like [`rash!`](macro@rash).
+///
+/// The script is run with `set -Eeuo pipefail` and `shopt -s inherit_errexit`, so a failing command
+/// stops it there and then, and comes back as a [`RashError::ScriptFailed`](enum@RashError) giving the
+/// line it was on, the command itself and its return value. See [`Command::strict`](crate::Command::strict) for the details.
+///
+/// # Examples
+///
+/// ```
+/// use rsbash::{rash_strict, RashError};
+///
+/// pub fn strict() -> Result<(), RashError> {
+///     assert_eq!(rash_strict!("echo hi")?, (0, "hi\n".to_string(), "".to_string()));
+///
+///     match rash_strict!("echo hi\ngrep -q bye <<< hi\necho bye") {
+///         Err(RashError::ScriptFailed { ret_val, line, command, .. }) => {
+///             assert_eq!((ret_val, line, command.as_str()), (1, 2, "grep -q bye <<< hi"));
+///         }
+///         other => panic!("{other:?}"),
+///     }
+///     Ok(())
+/// }
+/// ```
+///
+#[cfg(unix)]
+#[macro_export]
+macro_rules! rash_strict {
+    ($arg:expr) => {
+        $crate::shell::__strict_command($arg)
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use crate::RashError;
@@ -351,4 +457,23 @@ mod tests {
             ))
         }
     }
+
+    mod rash_strict {
+        use super::*;
+
+        #[test]
+        fn test_rash_strict_succeeds() -> Result<(), RashError> {
+            Ok(assert_eq!(rash_strict!(COMMAND)?, (0, "hi".to_string(), EMPTY_STRING.clone())))
+        }
+
+        #[test]
+        fn test_rash_strict_stops_at_the_first_failure() {
+            let error = rash_strict!("echo -n hi\nfalse\necho -n bye").unwrap_err();
+            assert!(matches!(
+                &error,
+                RashError::ScriptFailed { ret_val: 1, line: 2, command, .. } if command == "false"
+            ));
+            assert_eq!(error.stdout(), "hi");
+        }
+    }
 }
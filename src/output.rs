@@ -0,0 +1,196 @@
+use libc::{rusage, timeval};
+use std::time::{Duration, SystemTime};
+
+/// The output of a finished [`Command`](crate::Command).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// The return value of the command.
+    pub ret_val: i32,
+    /// Everything the command wrote to stdout.
+    pub stdout: String,
+    /// Everything the command wrote to stderr.
+    pub stderr: String,
+    /// The processes the command left running, which were killed once it exited.
+    /// Always empty unless the command was run with
+    /// [`Command::reap_orphans`](crate::Command::reap_orphans).
+    pub stragglers: Vec<Straggler>,
+    /// The return value of each stage of the last pipeline the command ran, like bash's
+    /// `PIPESTATUS`, if it was run with
+    /// [`Command::pipestatus`](crate::Command::pipestatus).
+    pub pipestatus: Option<Vec<i32>>,
+    /// Every command the script ran, in order, if it was run with
+    /// [`Command::trace`](crate::Command::trace).
+    pub trace: Option<Vec<TracedCommand>>,
+    /// The resources the command used.
+    pub usage: Usage,
+    /// When the command was started.
+    pub started: SystemTime,
+    /// When the command finished.
+    pub finished: SystemTime,
+    /// The version of the Landlock ABI the command was restricted with, if it was run with
+    /// [`Command::landlock`](crate::Command::landlock). `Some(0)` means the kernel doesn't
+    /// support Landlock, so the command ran unrestricted.
+    pub landlock_abi: Option<u32>,
+}
+
+/// A process left running by a [`Command`](crate::Command) after it exited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Straggler {
+    /// Its pid.
+    pub pid: i32,
+    /// Its name, as in `/proc/<pid>/comm`.
+    pub name: String,
+}
+
+/// A command run by a script traced with [`Command::trace`](crate::Command::trace), as
+/// reported by bash's `set -x`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracedCommand {
+    /// When it was run.
+    pub time: SystemTime,
+    /// The line of the script it was on, counting from 1. For a command over several lines,
+    /// this is its last.
+    pub line: u32,
+    /// How deeply it was nested, e.g. in a command substitution, counting from 1.
+    pub depth: usize,
+    /// The command after expansion, as bash printed it, e.g. `echo 'a b'`.
+    pub command: String,
+}
+
+impl TracedCommand {
+    /// The separator between the fields of [`TracedCommand::PS4`]: one which bash quotes if
+    /// it turns up in a command, so it can only come from the prompt.
+    pub(crate) const SEPARATOR: char = '\x1f';
+
+    /// The prompt bash prints before each command it traces, for [`TracedCommand::parse`] to
+    /// read back. Its first character is repeated once per level of nesting.
+    pub(crate) const PS4: &'static str = "+\x1f${EPOCHREALTIME}\x1f${LINENO}\x1f";
+
+    /// Read back what bash traced. A command with a newline in it carries on over more than
+    /// one line, until the next one starting with a prompt.
+    pub(crate) fn parse(trace: &[u8]) -> Vec<Self> {
+        let mut traced: Vec<Self> = Vec::new();
+        for line in String::from_utf8_lossy(trace).split_terminator('\n') {
+            match (Self::parse_line(line), traced.last_mut()) {
+                (Some(command), _) => traced.push(command),
+                (None, Some(last)) => {
+                    last.command.push('\n');
+                    last.command.push_str(line);
+                }
+                (None, None) => {}
+            }
+        }
+        traced
+    }
+
+    fn parse_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(4, Self::SEPARATOR);
+        let depth = fields.next().filter(|d| !d.is_empty() && d.bytes().all(|b| b == b'+'))?;
+        let (time, line, command) = (fields.next()?, fields.next()?, fields.next()?);
+        // `EPOCHREALTIME` follows the locale's decimal point.
+        let (secs, micros) = time.split_once(['.', ',']).unwrap_or((time, "0"));
+        let micros = format!("{micros:0<6}");
+        let time = Duration::new(secs.parse().ok()?, micros.get(..6)?.parse::<u32>().ok()? * 1000);
+        Some(Self {
+            time: SystemTime::UNIX_EPOCH + time,
+            line: line.parse().ok()?,
+            depth: depth.len(),
+            command: command.to_string(),
+        })
+    }
+}
+
+/// The resources used by a finished [`Command`](crate::Command), as reported by wait4(2).
+///
+/// This covers bash and every descendant it waited for, but not background jobs it left behind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// CPU time spent in user mode.
+    pub user_time: Duration,
+    /// CPU time spent in the kernel.
+    pub system_time: Duration,
+    /// The largest resident set size of any one process, in kilobytes.
+    pub max_rss: u64,
+    /// Page faults serviced without any I/O.
+    pub minor_faults: u64,
+    /// Page faults which needed I/O.
+    pub major_faults: u64,
+    /// Times a process gave up the CPU, e.g. to wait on I/O.
+    pub voluntary_context_switches: u64,
+    /// Times a process was preempted.
+    pub involuntary_context_switches: u64,
+}
+
+impl From<rusage> for Usage {
+    fn from(u: rusage) -> Self {
+        fn duration(t: timeval) -> Duration {
+            Duration::new(t.tv_sec.max(0) as u64, (t.tv_usec.max(0) as u32) * 1000)
+        }
+        Self {
+            user_time: duration(u.ru_utime),
+            system_time: duration(u.ru_stime),
+            max_rss: u.ru_maxrss.max(0) as u64,
+            minor_faults: u.ru_minflt.max(0) as u64,
+            major_faults: u.ru_majflt.max(0) as u64,
+            voluntary_context_switches: u.ru_nvcsw.max(0) as u64,
+            involuntary_context_switches: u.ru_nivcsw.max(0) as u64,
+        }
+    }
+}
+
+impl From<Output> for (i32, String, String) {
+    fn from(o: Output) -> Self {
+        (o.ret_val, o.stdout, o.stderr)
+    }
+}
+
+/// The output of a finished [`Pipeline`](crate::Pipeline).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineOutput {
+    /// The return value of the pipeline: that of its last stage, or with
+    /// [`Pipeline::pipefail`](crate::Pipeline::pipefail), of the last stage to fail.
+    pub ret_val: i32,
+    /// The return value of each stage, in order, like bash's `PIPESTATUS`. A stage killed by a
+    /// signal has 128 + the signal.
+    pub statuses: Vec<i32>,
+    /// Everything the last stage wrote to stdout.
+    pub stdout: String,
+    /// Everything each stage wrote to stderr, in order. Empty for stages whose stderr wasn't
+    /// [captured](crate::Stderr::Capture).
+    pub stderr: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traced(time: Duration, line: u32, depth: usize, command: &str) -> TracedCommand {
+        TracedCommand {
+            time: SystemTime::UNIX_EPOCH + time,
+            line,
+            depth,
+            command: command.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_traced_command_parse() {
+        let trace =
+            "+\x1f12.5\x1f1\x1fecho 'a\nb'\n++\x1f13,000001\x1f2\x1fecho\n+\x1f14\x1f3\x1fx=\n";
+        assert_eq!(
+            TracedCommand::parse(trace.as_bytes()),
+            [
+                traced(Duration::from_millis(12_500), 1, 1, "echo 'a\nb'"),
+                traced(Duration::new(13, 1000), 2, 2, "echo"),
+                traced(Duration::from_secs(14), 3, 1, "x="),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_traced_command_parse_skips_anything_else() {
+        let trace = "hello\n+ echo hi\n+\x1fnow\x1f1\x1fecho\n+\x1f1.0\x1f1\x1fecho\nbye";
+        let expected = traced(Duration::from_secs(1), 1, 1, "echo\nbye");
+        assert_eq!(TracedCommand::parse(trace.as_bytes()), [expected]);
+    }
+}
@@ -0,0 +1,560 @@
+use libc::{c_int, c_long, close, pipe2, O_CLOEXEC, SIGKILL, SIGPIPE};
+use std::{
+    any::Any,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write},
+    os::unix::io::FromRawFd,
+    sync::Arc,
+    thread::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    command::Command,
+    error::{ErrorContext, RashError},
+    output::PipelineOutput,
+    process::{Process, ProcessError},
+};
+
+/// A stage written in Rust, as given to [`Pipeline::pipe_with`].
+type StageFn = dyn Fn(&mut dyn BufRead, &mut dyn Write) -> io::Result<()> + Send + Sync;
+
+/// What the thread running a Rust stage comes back with: how the stage went, and its output
+/// if it was the last stage.
+type StageResult = (io::Result<()>, Vec<u8>);
+
+/// Commands run side by side, each one's stdout feeding the next one's stdin, as with bash's
+/// `|`.
+///
+/// Unlike writing `a | b` in one script, each stage is a [`Command`] of its own, with its own
+/// options - environment, working directory, where its stderr goes, and so on - and the return
+/// value of every stage comes back in [`PipelineOutput::statuses`]. The stages are wired
+/// together directly, so nothing passes through the host, except for stages written in Rust
+/// with [`Pipeline::pipe_with`].
+///
+/// ```
+/// use rsbash::{Command, RashError, Stderr};
+///
+/// pub fn piped() -> Result<(), RashError> {
+///     let output = Command::new("printf 'b\\na\\nc\\n'; echo oops >&2; exit 3")
+///         .pipe(Command::new("sort").env("LC_ALL", "C"))
+///         .pipe(Command::new("head -n1; echo done >&2").stderr(Stderr::Stdout))
+///         .output()?;
+///     assert_eq!(output.statuses, vec![3, 0, 0]);
+///     assert_eq!(output.ret_val, 0);
+///     assert_eq!(output.stdout, "a\ndone\n");
+///     assert_eq!(output.stderr, vec!["oops\n", "", ""]);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    pipefail: bool,
+}
+
+#[derive(Clone)]
+enum Stage {
+    Command(Box<Command>),
+    Rust(Arc<StageFn>),
+}
+
+impl fmt::Debug for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Command(command) => f.debug_tuple("Command").field(command).finish(),
+            Self::Rust(_) => f.write_str("Rust"),
+        }
+    }
+}
+
+impl Stage {
+    /// How the stage appears in errors.
+    fn description(&self) -> String {
+        match self {
+            Self::Command(command) => command.description(),
+            Self::Rust(_) => "<rust>".to_string(),
+        }
+    }
+}
+
+/// A stage which has been started.
+enum Running {
+    Process(Box<Process>),
+    Thread(JoinHandle<StageResult>),
+}
+
+impl Pipeline {
+    /// Start a pipeline whose first stage is `first`.
+    pub fn new(first: Command) -> Self {
+        Self {
+            stages: vec![Stage::Command(Box::new(first))],
+            pipefail: false,
+        }
+    }
+
+    /// Add `next` to the end of the pipeline, reading what was the last stage's stdout.
+    pub fn pipe(mut self, next: Command) -> Self {
+        self.stages.push(Stage::Command(Box::new(next)));
+        self
+    }
+
+    /// Add a stage written in Rust to the end of the pipeline, e.g. to filter or parse what
+    /// one command writes before the next one reads it.
+    ///
+    /// `stage` is called on a thread of its own with the last stage's stdout and the next
+    /// one's stdin, both buffered and connected by pipes, so a slow stage holds up the one
+    /// before it just as a slow command would. Both are closed once it returns, whether it has
+    /// read everything or not.
+    ///
+    /// A stage which returns `Ok` has a status of 0. One which fails writing because the next
+    /// stage has exited, like `head` does, has a status of 141, as if it were a command killed
+    /// by `SIGPIPE`. Any other error, or a panic, fails the pipeline with
+    /// [`RashError::StageFailed`].
+    ///
+    /// ```
+    /// use std::io::BufRead;
+    /// use rsbash::{Command, RashError};
+    ///
+    /// pub fn filtered() -> Result<(), RashError> {
+    ///     let output = Command::new("seq 10")
+    ///         .pipe_with(|input, output| {
+    ///             for line in input.lines() {
+    ///                 let n: u32 = line?.parse().map_err(std::io::Error::other)?;
+    ///                 writeln!(output, "{}", n * n)?;
+    ///             }
+    ///             Ok(())
+    ///         })
+    ///         .pipe(Command::new("tail -n2"))
+    ///         .output()?;
+    ///     assert_eq!(output.stdout, "81\n100\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn pipe_with<F>(mut self, stage: F) -> Self
+    where
+        F: Fn(&mut dyn BufRead, &mut dyn Write) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.stages.push(Stage::Rust(Arc::new(stage)));
+        self
+    }
+
+    /// Whether the pipeline's return value is that of the last stage to fail, as with bash's
+    /// `set -o pipefail`, rather than that of the last stage. Defaults to `false`.
+    pub fn pipefail(mut self, pipefail: bool) -> Self {
+        self.pipefail = pipefail;
+        self
+    }
+
+    /// Run every stage to completion, collecting their return values, the last one's stdout,
+    /// and each one's stderr.
+    ///
+    /// A command failing, even with 126 or 127, isn't an error: it's reported in
+    /// [`PipelineOutput::statuses`]. Errors are for a command which couldn't be spawned or
+    /// waited on, or which was killed for a system call its [`Seccomp`](crate::Seccomp)
+    /// filter denied, and for Rust stages which fail. They carry the whole pipeline, and the
+    /// pid and whatever output there was of the stage responsible. The stages after it are
+    /// dealt with according to their [`Command::on_drop`].
+    ///
+    /// A stage with [`Command::strict`], [`Command::pipestatus`] or [`Command::trace`] set is
+    /// rejected with [`RashError::InvalidOption`] before anything runs, as there'd be nowhere
+    /// to report what they found.
+    pub fn output(&self) -> Result<PipelineOutput, RashError> {
+        let start = Instant::now();
+        for stage in &self.stages {
+            if let Stage::Command(command) = stage {
+                if let Some(option) = command.side_channel_option() {
+                    let e = RashError::InvalidOption {
+                        message: format!(
+                            "`{option}` isn't supported on a pipeline stage, in `{}`",
+                            command.description()
+                        ),
+                        context: Box::default(),
+                    };
+                    return Err(self.error(e, None, start));
+                }
+            }
+        }
+        let mut running: Vec<Running> = Vec::with_capacity(self.stages.len());
+        // The read end of the pipe from the previous stage.
+        let mut stdin = -1;
+        for (i, stage) in self.stages.iter().enumerate() {
+            let mut pipe: [c_int; 2] = [-1, -1];
+            if i + 1 < self.stages.len() && unsafe { pipe2(pipe.as_mut_ptr(), O_CLOEXEC) } == -1 {
+                let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+                unsafe { close(stdin) };
+                Self::kill(&running);
+                let e = ProcessError::CouldNotCreatePipe(errno).into();
+                return Err(self.error(e, None, start));
+            }
+            match stage {
+                Stage::Command(command) => {
+                    let mut process = command.process().stdin_from(stdin).stdout_to(pipe[1]);
+                    stdin = pipe[0];
+                    if let Err(e) = command.open_process(&mut process) {
+                        unsafe { close(stdin) };
+                        Self::kill(&running);
+                        return Err(self.error(e, Some(&process), start));
+                    }
+                    running.push(Running::Process(Box::new(process)));
+                }
+                Stage::Rust(stage) => {
+                    running.push(Running::Thread(Self::thread(stage.clone(), stdin, pipe[1])));
+                    stdin = pipe[0];
+                }
+            }
+        }
+
+        let last = running.len() - 1;
+        let mut statuses = Vec::with_capacity(running.len());
+        let mut stdout = String::new();
+        let mut stderr = Vec::with_capacity(running.len());
+        for (i, stage) in running.into_iter().enumerate() {
+            match stage {
+                Running::Process(mut process) => {
+                    let closed = unsafe { process.close() };
+                    if let Some((pid, syscall, name)) = process.blocked_syscall() {
+                        let e = RashError::SyscallBlocked {
+                            syscall: syscall as c_long,
+                            pid,
+                            name,
+                            context: Box::default(),
+                        };
+                        return Err(self.error(e, Some(&process), start));
+                    }
+                    statuses.push(match closed {
+                        Ok(status) => status,
+                        Err(ProcessError::OpenDidNotCloseNormally(signal)) => 128 + signal,
+                        Err(e) => return Err(self.error(e.into(), Some(&process), start)),
+                    });
+                    let error = |e: ProcessError| self.error(e.into(), Some(&process), start);
+                    if i == last {
+                        stdout = process.stdout().map_err(error)?;
+                    }
+                    stderr.push(process.stderr().map_err(error)?);
+                }
+                Running::Thread(handle) => {
+                    let failed = |message: String, source: Option<io::Error>| {
+                        let e = RashError::StageFailed {
+                            stage: i,
+                            message,
+                            source,
+                            context: Box::default(),
+                        };
+                        self.error(e, None, start)
+                    };
+                    let (result, output) =
+                        handle.join().map_err(|panic| failed(Self::panic_message(panic), None))?;
+                    statuses.push(match result {
+                        Ok(()) => 0,
+                        Err(e) if e.kind() == ErrorKind::BrokenPipe => 128 + SIGPIPE,
+                        Err(e) => return Err(failed(e.to_string(), Some(e))),
+                    });
+                    if i == last {
+                        stdout = String::from_utf8(output).map_err(|e| {
+                            let e = RashError::FailedToReadStdout {
+                                message: e.to_string(),
+                                context: Box::default(),
+                            };
+                            self.error(e, None, start)
+                        })?;
+                    }
+                    stderr.push(String::new());
+                }
+            }
+        }
+
+        let ret_val = match self.pipefail {
+            true => statuses.iter().rev().find(|status| **status != 0).copied().unwrap_or(0),
+            false => *statuses.last().expect("A pipeline has at least one stage."),
+        };
+        Ok(PipelineOutput {
+            ret_val,
+            statuses,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Start `stage` on a thread of its own, reading from `stdin` and writing to `stdout` and
+    /// taking ownership of both. With no `stdin`, it reads nothing, and with no `stdout`, its
+    /// output is kept to be returned.
+    fn thread(stage: Arc<StageFn>, stdin: c_int, stdout: c_int) -> JoinHandle<StageResult> {
+        let input = (stdin != -1).then(|| unsafe { File::from_raw_fd(stdin) });
+        let output = (stdout != -1).then(|| unsafe { File::from_raw_fd(stdout) });
+        std::thread::spawn(move || {
+            let mut input: Box<dyn BufRead> = match input {
+                Some(file) => Box::new(BufReader::new(file)),
+                None => Box::new(io::empty()),
+            };
+            let mut kept = Vec::new();
+            let result = match output {
+                Some(file) => {
+                    let mut output = BufWriter::new(file);
+                    stage(&mut input, &mut output).and_then(|()| output.flush())
+                }
+                None => stage(&mut input, &mut kept),
+            };
+            (result, kept)
+        })
+    }
+
+    fn panic_message(panic: Box<dyn Any + Send>) -> String {
+        match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => match panic.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "panicked".to_string(),
+            },
+        }
+    }
+
+    /// Kill the commands started so far, before they're dropped, whatever their
+    /// [`Command::on_drop`] says, since the pipeline isn't going to run. Rust stages finish
+    /// once the pipes to and from them are closed.
+    fn kill(running: &[Running]) {
+        for stage in running {
+            if let Running::Process(process) = stage {
+                let _ = process.kill(SIGKILL);
+            }
+        }
+    }
+
+    /// `e` in the context of the pipeline, and of the stage responsible, if it's a command.
+    fn error(&self, e: RashError, process: Option<&Process>, start: Instant) -> RashError {
+        let (stdout, stderr) = process.map(Process::partial_output).unwrap_or_default();
+        e.with_context(ErrorContext {
+            command: Some(
+                self.stages.iter().map(Stage::description).collect::<Vec<_>>().join(" | "),
+            ),
+            pid: process.and_then(Process::pid),
+            elapsed: Some(start.elapsed()),
+            stdout,
+            stderr,
+            trace: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, BufRead},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::Pipeline;
+    use crate::{error::SpawnStage, process::Stderr, Command, RashError, Seccomp};
+
+    #[test]
+    fn test_pipeline_single_stage() -> Result<(), RashError> {
+        let output = Pipeline::new(Command::new("echo hi; exit 2")).output()?;
+        assert_eq!((output.ret_val, output.statuses), (2, vec![2]));
+        Ok(assert_eq!(output.stdout, "hi\n"))
+    }
+
+    #[test]
+    fn test_pipeline_reports_every_status() -> Result<(), RashError> {
+        let output = Command::new("echo hi; exit 1")
+            .pipe(Command::new("cat; exit 2"))
+            .pipe(Command::new("tr a-z A-Z"))
+            .output()?;
+        assert_eq!(output.statuses, vec![1, 2, 0]);
+        assert_eq!(output.ret_val, 0);
+        Ok(assert_eq!(output.stdout, "HI\n"))
+    }
+
+    #[test]
+    fn test_pipeline_rejects_stages_which_report() {
+        let stages = [
+            Command::new("false").strict(true),
+            Command::new("false").pipestatus(true),
+            Command::new("false").trace(true),
+        ];
+        for (stage, option) in stages.into_iter().zip(["strict", "pipestatus", "trace"]) {
+            let error = Command::new("echo hi").pipe(stage).output().err().unwrap();
+            match &error {
+                RashError::InvalidOption {
+                    message,
+                    ..
+                } => assert!(message.contains(option)),
+                _ => panic!("Expected InvalidOption, got {error:?}"),
+            }
+            assert_eq!(error.command(), Some("echo hi | false"));
+        }
+    }
+
+    #[test]
+    fn test_pipeline_pipefail() -> Result<(), RashError> {
+        let pipeline =
+            Command::new("exit 1").pipe(Command::new("exit 2")).pipe(Command::new("true"));
+        Ok(assert_eq!(pipeline.pipefail(true).output()?.ret_val, 2))
+    }
+
+    #[test]
+    fn test_pipeline_reports_stages_killed_by_sigpipe() -> Result<(), RashError> {
+        let output = Command::new("exec yes").pipe(Command::new("head -n1")).output()?;
+        assert_eq!(output.statuses, vec![128 + libc::SIGPIPE, 0]);
+        Ok(assert_eq!(output.stdout, "y\n"))
+    }
+
+    #[test]
+    fn test_pipeline_stages_have_their_own_options() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let output = Command::new("echo $GREETING; pwd")
+            .env("GREETING", "hi")
+            .current_dir(dir.path())
+            .pipe(Command::new("cat; echo \"${GREETING-unset}\"; pwd"))
+            .output()?;
+        let dir = dir.path().canonicalize()?;
+        let cwd = std::env::current_dir()?;
+        Ok(assert_eq!(output.stdout, format!("hi\n{}\nunset\n{}\n", dir.display(), cwd.display())))
+    }
+
+    #[test]
+    fn test_pipeline_stderr() -> Result<(), RashError> {
+        let output = Command::new("echo one >&2")
+            .pipe(Command::new("cat; echo two >&2").stderr(Stderr::Stdout))
+            .pipe(Command::new("cat; echo three >&2").stderr(Stderr::Null))
+            .pipe(Command::new("cat; echo four >&2"))
+            .output()?;
+        assert_eq!(output.stdout, "two\n");
+        Ok(assert_eq!(output.stderr, vec!["one\n", "", "", "four\n"]))
+    }
+
+    #[test]
+    fn test_pipeline_stages_run_side_by_side() -> Result<(), RashError> {
+        let start = Instant::now();
+        let output =
+            Command::new("sleep 0.3; echo hi").pipe(Command::new("sleep 0.3; cat")).output()?;
+        assert_eq!(output.stdout, "hi\n");
+        Ok(assert!(start.elapsed() < Duration::from_millis(550)))
+    }
+
+    #[test]
+    fn test_pipeline_reports_a_stage_which_couldnt_be_spawned() {
+        let error = Command::new("sleep 10")
+            .pipe(Command::new("cat").current_dir("/i/do/not/exist"))
+            .output()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SpawnFailed {
+                stage: SpawnStage::Chdir,
+                ..
+            }
+        ));
+        assert_eq!(error.context().command.as_deref(), Some("sleep 10 | cat"));
+    }
+
+    #[test]
+    fn test_pipeline_reports_blocked_syscalls() {
+        let error = Command::new("echo hi")
+            .pipe(
+                Command::new("cat; mkdir /tmp/nope")
+                    .seccomp(Seccomp::allow_all().deny(libc::SYS_mkdir).deny(libc::SYS_mkdirat)),
+            )
+            .output()
+            .unwrap_err();
+        assert!(matches!(error, RashError::SyscallBlocked { .. }));
+    }
+
+    #[test]
+    fn test_pipeline_rust_stage() -> Result<(), RashError> {
+        let output = Command::new("printf 'a\\nb\\nc\\n'")
+            .pipe_with(|input, output| {
+                for line in input.lines() {
+                    writeln!(output, "{}", line?.to_uppercase())?;
+                }
+                Ok(())
+            })
+            .pipe(Command::new("tac"))
+            .output()?;
+        assert_eq!(output.statuses, vec![0, 0, 0]);
+        assert_eq!(output.stderr, vec!["", "", ""]);
+        Ok(assert_eq!(output.stdout, "C\nB\nA\n"))
+    }
+
+    #[test]
+    fn test_pipeline_rust_stage_last() -> Result<(), RashError> {
+        let output = Command::new("echo hi")
+            .pipe_with(|input, output| io::copy(input, output).map(|_| ()))
+            .pipe_with(|input, output| {
+                let mut line = String::new();
+                input.read_to_string(&mut line)?;
+                write!(output, "{}", line.trim_end().len())
+            })
+            .output()?;
+        Ok(assert_eq!((output.statuses, output.stdout), (vec![0, 0, 0], "2".to_string())))
+    }
+
+    #[test]
+    fn test_pipeline_rust_stage_has_backpressure() -> Result<(), RashError> {
+        // The stage only stops reading because its writes block once the pipe to head is full,
+        // and then fail once head has exited. So it reads no more than fits in between.
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = read.clone();
+        let output = Command::new("exec yes")
+            .pipe_with(move |input, output| {
+                for line in input.lines() {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    writeln!(output, "{}", line?)?;
+                }
+                Ok(())
+            })
+            .pipe(Command::new("head -n1"))
+            .output()?;
+        assert_eq!(output.statuses, vec![128 + libc::SIGPIPE, 128 + libc::SIGPIPE, 0]);
+        assert_eq!(output.stdout, "y\n");
+        Ok(assert!(read.load(Ordering::Relaxed) < 1 << 20))
+    }
+
+    #[test]
+    fn test_pipeline_rust_stage_error() {
+        let error = Command::new("seq 100000")
+            .pipe_with(|_, _| Err(io::Error::other("bad input")))
+            .pipe(Command::new("cat"))
+            .output()
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            RashError::StageFailed {
+                stage: 1,
+                message,
+                source: Some(_),
+                ..
+            } if message == "bad input"
+        ));
+        assert_eq!(error.command(), Some("seq 100000 | <rust> | cat"));
+    }
+
+    #[test]
+    fn test_pipeline_rust_stage_panic() {
+        let error = Command::new("echo hi").pipe_with(|_, _| panic!("oh no")).output().unwrap_err();
+        assert!(matches!(
+            &error,
+            RashError::StageFailed {
+                stage: 1,
+                message,
+                source: None,
+                ..
+            } if message == "oh no"
+        ));
+    }
+
+    #[test]
+    fn test_pipeline_rust_stage_rerun() -> Result<(), RashError> {
+        let pipeline = Command::new("echo hi").pipe_with(|input, output| {
+            let mut contents = Vec::new();
+            input.read_to_end(&mut contents)?;
+            output.write_all(&contents)
+        });
+        assert_eq!(pipeline.output()?.stdout, "hi\n");
+        Ok(assert_eq!(pipeline.clone().output()?.stdout, "hi\n"))
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    command::Command,
+    error::{ErrorContext, RashError},
+    session::Session,
+};
+
+/// Records the state a fresh shell starts in, for [`RESET`] to go back to.
+const SNAPSHOT: &str = "__rash_pool_options=$(set +o; shopt -p)
+__rash_pool_env=$(declare -px)
+__rash_pool_traps=$(trap -p)
+__rash_pool_cwd=$PWD";
+
+/// Puts a shell back the way [`SNAPSHOT`] found it. The options go first, so that a leftover
+/// `set -e` or `set -u` can't kill the shell part way through.
+const RESET: &str = "builtin eval \"$__rash_pool_options\"
+for __rash_name in $(compgen -e); do builtin unset -v \"$__rash_name\"; done 2>/dev/null
+builtin eval \"$__rash_pool_env\" 2>/dev/null
+builtin trap - $(compgen -A signal) EXIT ERR DEBUG RETURN
+builtin eval \"$__rash_pool_traps\"
+builtin cd -- \"$__rash_pool_cwd\"";
+
+/// A fixed number of warm [`Session`]s, handed out one at a time, so that running lots of
+/// short scripts doesn't pay for starting bash each time.
+///
+/// [`get`](SessionPool::get) checks a session out, blocking until one's free. When the
+/// [`PooledSession`] is dropped, the session is reset and returned to the pool: its working
+/// directory, exported environment, traps and shell options go back to how they were when the
+/// shell started, and its [timeout](Session::set_timeout) is cleared. Anything else - unexported variables, functions - is left as it is. A
+/// session whose shell died while it was checked out, or which can't be reset, is replaced
+/// with a new one.
+///
+/// The pool can be shared between threads by reference.
+///
+/// ```
+/// use rsbash::{RashError, SessionPool};
+///
+/// pub fn pooled() -> Result<(), RashError> {
+///     let pool = SessionPool::new(2)?;
+///     std::thread::scope(|scope| {
+///         for i in 0..4 {
+///             let pool = &pool;
+///             scope.spawn(move || -> Result<(), RashError> {
+///                 let mut session = pool.get()?;
+///                 assert_eq!(session.run(format!("cd /tmp; echo {i}"))?.stdout, format!("{i}\n"));
+///                 Ok(())
+///             });
+///         }
+///     });
+///     assert_eq!(pool.metrics().checkouts, 4);
+///     Ok(())
+/// }
+/// ```
+pub struct SessionPool {
+    command: Command,
+    size: usize,
+    state: Mutex<State>,
+    returned: Condvar,
+}
+
+struct State {
+    idle: Vec<Session>,
+    /// How many sessions there are, idle or checked out, or being started.
+    live: usize,
+    metrics: PoolMetrics,
+}
+
+/// What a [`SessionPool`] has been up to, as returned by [`SessionPool::metrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// How many sessions the pool keeps.
+    pub size: usize,
+    /// How many sessions are waiting to be checked out.
+    pub idle: usize,
+    /// How many sessions are checked out.
+    pub in_use: usize,
+    /// How many times a session has been checked out.
+    pub checkouts: u64,
+    /// How many shells have been started, including the ones the pool started with.
+    pub started: u64,
+    /// How many sessions have been replaced, because their shell died or couldn't be reset.
+    pub replaced: u64,
+    /// How long [`SessionPool::get`] has spent waiting for a session to be free, in total.
+    pub waited: Duration,
+}
+
+impl SessionPool {
+    /// Start a pool of `size` sessions in plain bash shells.
+    ///
+    /// Returns [`RashError::InvalidOption`] if `size` is 0, as nothing could ever be checked
+    /// out of it.
+    pub fn new(size: usize) -> Result<Self, RashError> {
+        Command::new("").session_pool(size)
+    }
+
+    /// Start a pool of `size` sessions spawned from `command`. See
+    /// [`Command::session_pool`].
+    pub(crate) fn start(command: Command, size: usize) -> Result<Self, RashError> {
+        if size == 0 {
+            let error = RashError::InvalidOption {
+                message: "a session pool needs at least one session".to_string(),
+                context: Box::default(),
+            };
+            return Err(error.with_context(ErrorContext {
+                command: Some(command.description()),
+                ..ErrorContext::default()
+            }));
+        }
+        let pool = Self {
+            command,
+            size,
+            state: Mutex::new(State {
+                idle: Vec::with_capacity(size),
+                live: 0,
+                metrics: PoolMetrics {
+                    size,
+                    ..PoolMetrics::default()
+                },
+            }),
+            returned: Condvar::new(),
+        };
+        for _ in 0..size {
+            let session = pool.session()?;
+            let mut state = pool.lock();
+            state.idle.push(session);
+            state.live += 1;
+        }
+        Ok(pool)
+    }
+
+    /// Check a session out, waiting until one is free. It's returned to the pool when the
+    /// [`PooledSession`] is dropped.
+    ///
+    /// Returns an error if a session had to be replaced, and the new one couldn't be started.
+    pub fn get(&self) -> Result<PooledSession<'_>, RashError> {
+        let start = Instant::now();
+        let mut state = self.lock();
+        loop {
+            if let Some(session) = state.idle.pop() {
+                state.metrics.checkouts += 1;
+                state.metrics.waited += start.elapsed();
+                return Ok(self.checked_out(session));
+            }
+            if state.live < self.size {
+                break;
+            }
+            state = self.returned.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        // One was lost before it could be replaced, so start its replacement now.
+        state.live += 1;
+        drop(state);
+        let session = self.session();
+        let mut state = self.lock();
+        match session {
+            Ok(session) => {
+                state.metrics.checkouts += 1;
+                state.metrics.waited += start.elapsed();
+                Ok(self.checked_out(session))
+            }
+            Err(e) => {
+                state.live -= 1;
+                self.returned.notify_one();
+                Err(e)
+            }
+        }
+    }
+
+    /// How the pool is doing.
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.lock();
+        PoolMetrics {
+            idle: state.idle.len(),
+            in_use: state.live - state.idle.len(),
+            ..state.metrics
+        }
+    }
+
+    fn checked_out(&self, session: Session) -> PooledSession<'_> {
+        PooledSession {
+            pool: self,
+            restarts: session.restarts(),
+            session: Some(session),
+        }
+    }
+
+    /// Start a new session, with a snapshot of its state to be reset to.
+    fn session(&self) -> Result<Session, RashError> {
+        let mut session = self.command.session()?;
+        self.lock().metrics.started += 1;
+        session.run(SNAPSHOT)?;
+        Ok(session)
+    }
+
+    /// Take a session back, resetting it or replacing it.
+    fn put(&self, mut session: Session, restarts: usize) {
+        session.set_timeout(None);
+        let reset = session.pid().is_some()
+            && session.restarts() == restarts
+            && session.run(RESET).is_ok_and(|output| output.ret_val == 0);
+        let session = match reset {
+            true => Some(session),
+            false => {
+                drop(session);
+                self.lock().metrics.replaced += 1;
+                self.session().ok()
+            }
+        };
+        let mut state = self.lock();
+        match session {
+            Some(session) => state.idle.push(session),
+            // Started again by the next `get` which needs it.
+            None => state.live -= 1,
+        }
+        self.returned.notify_one();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Nothing panics while holding the lock, but there's no harm carrying on if it did.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A [`Session`] checked out of a [`SessionPool`], which it goes back to when dropped.
+pub struct PooledSession<'a> {
+    pool: &'a SessionPool,
+    /// How many times the shell had been restarted when it was checked out, to tell whether
+    /// it died while it was out.
+    restarts: usize,
+    session: Option<Session>,
+}
+
+impl Deref for PooledSession<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        self.session.as_ref().expect("Only taken on drop.")
+    }
+}
+
+impl DerefMut for PooledSession<'_> {
+    fn deref_mut(&mut self) -> &mut Session {
+        self.session.as_mut().expect("Only taken on drop.")
+    }
+}
+
+impl Drop for PooledSession<'_> {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.pool.put(session, self.restarts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::SessionPool;
+    use crate::{Command, RashError};
+
+    #[test]
+    fn test_pool_rejects_a_size_of_zero() {
+        let error = SessionPool::new(0).err().unwrap();
+        assert!(matches!(error, RashError::InvalidOption { .. }));
+        let error = Command::new("x=1").session_pool(0).err().unwrap();
+        assert_eq!(error.command(), Some("x=1"));
+    }
+
+    #[test]
+    fn test_pool_starts_warm() -> Result<(), RashError> {
+        let pool = SessionPool::new(3)?;
+        let metrics = pool.metrics();
+        assert_eq!((metrics.size, metrics.idle, metrics.in_use, metrics.started), (3, 3, 0, 3));
+        let session = pool.get()?;
+        assert_eq!((pool.metrics().idle, pool.metrics().in_use), (2, 1));
+        drop(session);
+        Ok(assert_eq!((pool.metrics().idle, pool.metrics().checkouts), (3, 1)))
+    }
+
+    #[test]
+    fn test_pool_resets_sessions() -> Result<(), RashError> {
+        let pool = SessionPool::new(1)?;
+        let pid = {
+            let mut session = pool.get()?;
+            session.run("cd /; export LEFTOVER=1; export HOME=/nowhere; trap 'echo bye' EXIT")?;
+            session.run("set -eu -o pipefail; shopt -s nullglob")?;
+            session.pid()
+        };
+        let mut session = pool.get()?;
+        assert_eq!(session.pid(), pid);
+        assert_eq!(session.cwd()?, std::env::current_dir().unwrap());
+        assert_eq!(session.var("LEFTOVER")?, None);
+        assert_eq!(session.var("HOME")?, std::env::var("HOME").ok());
+        assert_eq!(session.run("trap -p")?.stdout, "");
+        assert_eq!(
+            session.run("shopt -p nullglob; false; echo $?")?.stdout,
+            "shopt -u nullglob\n1\n"
+        );
+        Ok(assert_eq!(pool.metrics().replaced, 0))
+    }
+
+    #[test]
+    fn test_pool_resets_timeouts() -> Result<(), RashError> {
+        let pool = SessionPool::new(1)?;
+        pool.get()?.set_timeout(Some(Duration::from_millis(100)));
+        let mut session = pool.get()?;
+        assert_eq!(session.timeout(), None);
+        session.run("sleep 0.2")?;
+        Ok(assert_eq!(pool.metrics().replaced, 0))
+    }
+
+    #[test]
+    fn test_pool_replaces_dead_sessions() -> Result<(), RashError> {
+        let pool = SessionPool::new(1)?;
+        let pid = {
+            let mut session = pool.get()?;
+            assert!(session.run("exit 1").is_err());
+            session.run("x=1")?;
+            session.pid()
+        };
+        let mut session = pool.get()?;
+        assert_ne!(session.pid(), pid);
+        assert_eq!(session.var("x")?, None);
+        let metrics = pool.metrics();
+        Ok(assert_eq!((metrics.replaced, metrics.started), (1, 2)))
+    }
+
+    #[test]
+    fn test_pool_blocks_until_a_session_is_free() -> Result<(), RashError> {
+        let pool = SessionPool::new(1)?;
+        std::thread::scope(|scope| -> Result<(), RashError> {
+            let session = pool.get()?;
+            let waiter = scope.spawn(|| pool.get().map(|mut session| session.run("echo hi")));
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(!waiter.is_finished());
+            drop(session);
+            assert_eq!(waiter.join().unwrap()??.stdout, "hi\n");
+            Ok(())
+        })?;
+        Ok(assert!(pool.metrics().waited >= Duration::from_millis(100)))
+    }
+
+    #[test]
+    fn test_pool_from_command() -> Result<(), RashError> {
+        let pool = Command::new("export GREETING=hi").current_dir("/tmp").session_pool(1)?;
+        {
+            let mut session = pool.get()?;
+            session.run("cd /; export GREETING=bye")?;
+        }
+        let mut session = pool.get()?;
+        assert_eq!(session.cwd()?, PathBuf::from("/tmp"));
+        Ok(assert_eq!(session.var("GREETING")?.as_deref(), Some("hi")))
+    }
+
+    #[test]
+    fn test_pool_is_shareable() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<SessionPool>();
+    }
+}
@@ -1,27 +1,33 @@
-use libc::{_exit, c_char, c_int, close, dup, execl, fork, pipe, waitpid, WEXITSTATUS, WIFEXITED};
+use libc::{
+    c_int, c_void, close, fcntl, kill, open, pipe2, poll, pollfd, read, rusage, setpgid, wait4,
+    EAGAIN, EINTR, F_DUPFD_CLOEXEC, O_CLOEXEC, O_WRONLY, POLLIN, SIGKILL, WEXITSTATUS, WIFEXITED,
+    WTERMSIG,
+};
 use std::{
-    ffi::CString,
     fs::File,
-    io::Read,
+    io::{ErrorKind, Read},
+    mem::{size_of, MaybeUninit},
     os::unix::io::FromRawFd,
-    sync::{Arc, Condvar, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread::JoinHandle,
-    time::Duration,
+    time::SystemTime,
 };
 use thiserror::Error;
 
-use crate::command::BashCommand;
-
-lazy_static! {
-    static ref SHELL_PATH: CString = CString::new("/bin/sh").expect("/bin/sh CString failed.");
-    static ref SH: CString = CString::new("sh").expect("sh CString failed.");
-    static ref COMMAND: CString = CString::new("-c").expect("-c CString failed.");
-}
+use crate::{
+    child::{ChildPlan, SpawnReport, SupervisorRecord},
+    error::SpawnStage,
+    forward::Forwarding,
+    output::{Straggler, Usage},
+};
 
 struct Reader {
-    contents: String,
-    handle: Option<JoinHandle<String>>,
-    pair: Arc<(Mutex<bool>, Condvar)>,
+    contents: Vec<u8>,
+    handle: Option<JoinHandle<(Vec<u8>, Option<ReaderError>)>>,
+    abort: Arc<AtomicBool>,
 }
 
 #[derive(Error, Debug)]
@@ -35,80 +41,165 @@ pub(crate) enum ReaderError {
 }
 
 impl Reader {
+    /// How long the reader thread blocks for before checking whether it's been aborted.
+    const POLL_TIMEOUT_MS: c_int = 25;
+
     pub(crate) fn new() -> Self {
         Self {
-            contents: String::default(),
+            contents: Vec::default(),
             handle: None,
-            pair: Arc::new((Mutex::new(false), Condvar::new())),
+            abort: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Read `fd` to EOF on a new thread, taking ownership of it.
     pub(crate) unsafe fn read(&mut self, fd: c_int) -> Result<(), ReaderError> {
-        let pair = self.pair.clone();
+        let abort = self.abort.clone();
         let mut file = File::from_raw_fd(fd);
         self.handle = Some(std::thread::spawn(move || {
-            let mut contents = String::default();
-            let &(ref lock, ref cvar) = &*pair;
-            loop {
-                file.read_to_string(&mut contents)
-                    .map_err(|e| ReaderError::CouldNotRead(e.to_string()))
-                    .unwrap();
-                let mut stop = lock.lock().unwrap();
-                let result = cvar.wait_timeout(stop, Duration::from_millis(25)).unwrap();
-                stop = result.0;
-                if *stop == true {
-                    break;
+            // Bytes rather than a String, so that whatever we did manage to read can still be
+            // reported if the stream turns out not to be UTF-8, or the read fails part way.
+            let mut contents = Vec::default();
+            let mut buffer = [0u8; 8192];
+            let mut poll_fd = pollfd {
+                fd,
+                events: POLLIN,
+                revents: 0,
+            };
+            while !abort.load(Ordering::Relaxed) {
+                match poll(&mut poll_fd, 1, Self::POLL_TIMEOUT_MS) {
+                    0 => continue,
+                    -1 if errno() == EINTR => continue,
+                    -1 => {
+                        let error = std::io::Error::last_os_error().to_string();
+                        return (contents, Some(ReaderError::CouldNotRead(error)));
+                    }
+                    _ => match file.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(n) => contents.extend_from_slice(&buffer[..n]),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            return (contents, Some(ReaderError::CouldNotRead(e.to_string())))
+                        }
+                    },
                 }
             }
-            contents
+            (contents, None)
         }));
         Ok(())
     }
 
-    pub(crate) fn stop(&mut self) -> () {
-        let &(ref lock, ref cvar) = &*self.pair;
-        {
-            let mut stop = lock.lock().unwrap();
-            *stop = true;
-        }
-        cvar.notify_one();
+    /// Have the reader thread give up without waiting for EOF, e.g. because a background job
+    /// of a killed child is still holding the pipe open.
+    pub(crate) fn abort(&mut self) {
+        self.abort.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether there's a reader thread to join.
+    pub(crate) fn reading(&self) -> bool {
+        self.handle.is_some()
     }
 
     pub(crate) fn join(&mut self) -> Result<(), ReaderError> {
-        Ok(self.contents = self
+        let (contents, error) = self
             .handle
             .take()
             .ok_or(ReaderError::PrematureJoin)?
             .join()
-            .map_err(|e| ReaderError::ThreadError(format!("{:?}", e)))?)
+            .map_err(|e| ReaderError::ThreadError(format!("{:?}", e)))?;
+        self.contents = contents;
+        error.map_or(Ok(()), Err)
+    }
+
+    pub(crate) fn contents(&self) -> Result<String, ReaderError> {
+        String::from_utf8(self.contents.clone())
+            .map_err(|e| ReaderError::CouldNotRead(e.to_string()))
     }
 
-    pub(crate) fn contents(&self) -> String {
-        self.contents.clone()
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.contents
+    }
+
+    /// Whatever has been read, even if it isn't valid UTF-8.
+    pub(crate) fn partial(&self) -> String {
+        String::from_utf8_lossy(&self.contents).into_owned()
     }
 }
 
+/// What to do with a child process which is still running when its handle is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnDrop {
+    /// Kill it with `SIGKILL`, and reap it.
+    #[default]
+    Kill,
+    /// Block until it exits, and reap it.
+    Wait,
+    /// Leave it running, and have a background thread reap it once it exits.
+    Detach,
+}
+
+/// Where a child's stderr goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Stderr {
+    /// Read it, to be returned alongside stdout.
+    #[default]
+    Capture,
+    /// Throw it away, as with `2>/dev/null`.
+    Null,
+    /// Send it wherever stdout goes, as with `2>&1`.
+    Stdout,
+}
+
 pub(crate) struct Process {
     fds: [c_int; 3],
     pid: c_int,
+    /// Where signals go: the script itself, even when `pid` is its supervisor.
+    script: c_int,
+    running: bool,
+    on_drop: OnDrop,
+    /// Whether stdout and stderr are left for the caller to read as the child runs, rather
+    /// than being read to EOF in the background.
+    streaming: bool,
+    /// An fd to give the child as its stdin, rather than a pipe from us.
+    stdin_from: c_int,
+    /// An fd to give the child as its stdout, rather than a pipe to us.
+    stdout_to: c_int,
+    stderr_to: Stderr,
+    /// Pipes for the script to report back through, besides its stdio: it inherits the write
+    /// end of each, and we read the other like stdout.
+    side_channels: Vec<[c_int; 2]>,
     stdout: Reader,
     stderr: Reader,
+    stragglers: Option<Reader>,
+    sides: Vec<Reader>,
+    forwarding: Option<Forwarding>,
+    started: SystemTime,
+    finished: SystemTime,
+    usage: Usage,
 }
 
 #[derive(Error, Debug, PartialEq)]
 pub(crate) enum ProcessError {
-    #[error("Couldn't fork.")]
-    CouldNotFork,
-    #[error("Couldn't create pipe.")]
-    CouldNotCreatePipe,
-    #[error("Couldn't dup fd {0}")]
-    CouldNotDupFd(c_int),
-    #[error("process::open didn't close normally - WIFEXITED was false.")]
-    OpenDidNotCloseNormally,
+    #[error("Couldn't fork - errno {0}.")]
+    CouldNotFork(c_int),
+    #[error("Couldn't create pipe - errno {0}.")]
+    CouldNotCreatePipe(c_int),
+    #[error("Couldn't open /dev/null - errno {0}.")]
+    CouldNotOpenNull(c_int),
+    #[error("Couldn't wait for the child - errno {0}.")]
+    CouldNotWait(c_int),
+    #[error("Couldn't signal the child - errno {0}.")]
+    CouldNotKill(c_int),
+    #[error("process::open didn't close normally - killed by signal {0}.")]
+    OpenDidNotCloseNormally(c_int),
     #[error("Couldn't get stderr.")]
     CouldNotGetStderr,
     #[error("Couldn't get stdout.")]
     CouldNotGetStdout,
+    #[error("Couldn't spawn - {0} failed with errno {1}.")]
+    SpawnFailed(SpawnStage, c_int),
+    #[error("Interrupted by signal {0}.")]
+    Interrupted(c_int),
 }
 
 impl Process {
@@ -116,85 +207,242 @@ impl Process {
         Self {
             fds: [-1, -1, -1],
             pid: -1,
+            script: -1,
+            running: false,
+            on_drop: OnDrop::default(),
+            streaming: false,
+            stdin_from: -1,
+            stdout_to: -1,
+            stderr_to: Stderr::default(),
+            side_channels: Vec::new(),
             stdout: Reader::new(),
             stderr: Reader::new(),
+            stragglers: None,
+            sides: Vec::new(),
+            forwarding: None,
+            started: SystemTime::UNIX_EPOCH,
+            finished: SystemTime::UNIX_EPOCH,
+            usage: Usage::default(),
         }
     }
 
-    pub(crate) unsafe fn open(&mut self, command: BashCommand) -> Result<(), ProcessError> {
+    pub(crate) unsafe fn open<P: Into<ChildPlan>>(&mut self, plan: P) -> Result<(), ProcessError> {
+        let mut plan = plan.into();
+        let side_channels = std::mem::take(&mut self.side_channels);
+        for pipe in &side_channels {
+            plan.inherit(pipe[1]);
+        }
         let mut in_fds: [c_int; 2] = [-1, -1];
         let mut out_fds: [c_int; 2] = [-1, -1];
         let mut err_fds: [c_int; 2] = [-1, -1];
+        let mut report_fds: [c_int; 2] = [-1, -1];
+        let mut straggler_fds: [c_int; 2] = [-1, -1];
 
         unsafe fn close_pipe(pipe: &[c_int; 2]) {
             close(pipe[0]);
             close(pipe[1]);
         }
 
-        self.pipe(&mut in_fds, || {})?;
+        unsafe fn close_pipes(pipes: &[[c_int; 2]]) {
+            for pipe in pipes {
+                close_pipe(pipe);
+            }
+        }
+
+        // Whichever fds we were given are closed along with the pipes from here on.
+        let stdin_from = std::mem::replace(&mut self.stdin_from, -1);
+        let stdout_to = std::mem::replace(&mut self.stdout_to, -1);
+        match stdin_from {
+            -1 => self.pipe(&mut in_fds, || {
+                close(stdout_to);
+                close_pipes(&side_channels);
+            })?,
+            fd => in_fds[0] = fd,
+        }
 
-        self.pipe(&mut out_fds, || {
-            close_pipe(&in_fds);
-        })?;
+        match stdout_to {
+            -1 => self.pipe(&mut out_fds, || {
+                close_pipe(&in_fds);
+                close_pipes(&side_channels);
+            })?,
+            fd => out_fds[1] = fd,
+        }
 
-        self.pipe(&mut err_fds, || {
+        match self.stderr_to {
+            Stderr::Capture => self.pipe(&mut err_fds, || {
+                close_pipe(&out_fds);
+                close_pipe(&in_fds);
+                close_pipes(&side_channels);
+            })?,
+            Stderr::Null => match open(c"/dev/null".as_ptr(), O_WRONLY | O_CLOEXEC) {
+                -1 => {
+                    let errno = errno();
+                    close_pipe(&out_fds);
+                    close_pipe(&in_fds);
+                    close_pipes(&side_channels);
+                    return Err(ProcessError::CouldNotOpenNull(errno));
+                }
+                fd => err_fds[1] = fd,
+            },
+            // The child's stdout is dup'd onto both.
+            Stderr::Stdout => {}
+        }
+
+        self.pipe(&mut report_fds, || {
+            close_pipe(&err_fds);
             close_pipe(&out_fds);
             close_pipe(&in_fds);
+            close_pipes(&side_channels);
         })?;
 
-        match fork() {
+        if plan.supervised() {
+            self.pipe(&mut straggler_fds, || {
+                close_pipe(&report_fds);
+                close_pipe(&err_fds);
+                close_pipe(&out_fds);
+                close_pipe(&in_fds);
+                close_pipes(&side_channels);
+            })?;
+        }
+
+        // Registered before forking, so that a signal which arrives in between isn't lost.
+        // Running out of slots is a resource limit much like fork's own EAGAIN.
+        let forwarding = match plan.forwards_signals() {
+            true => match Forwarding::register() {
+                Some(forwarding) => Some(forwarding),
+                None => {
+                    close_pipe(&straggler_fds);
+                    close_pipe(&report_fds);
+                    close_pipe(&err_fds);
+                    close_pipe(&out_fds);
+                    close_pipe(&in_fds);
+                    close_pipes(&side_channels);
+                    return Err(ProcessError::CouldNotFork(EAGAIN));
+                }
+            },
+            false => None,
+        };
+
+        let stdio = [
+            in_fds[0],
+            out_fds[1],
+            match self.stderr_to {
+                Stderr::Stdout => out_fds[1],
+                _ => err_fds[1],
+            },
+        ];
+        let parent_ends = [in_fds[1], out_fds[0], err_fds[0]];
+        self.started = SystemTime::now();
+        match plan.fork(stdio, parent_ends, report_fds[1], straggler_fds[1]) {
             -1 => {
+                let errno = errno();
+                if plan.supervised() {
+                    close_pipe(&straggler_fds);
+                }
+                close_pipe(&report_fds);
                 close_pipe(&err_fds);
                 close_pipe(&out_fds);
                 close_pipe(&in_fds);
-                Err(ProcessError::CouldNotFork)
-            }
-            0 => {
-                close(in_fds[1]);
-                close(out_fds[0]);
-                close(err_fds[0]);
-
-                close(0);
-                self.dup(in_fds[0])?;
-
-                close(1);
-                self.dup(out_fds[1])?;
-
-                close(2);
-                self.dup(err_fds[1])?;
-
-                execl(
-                    SHELL_PATH.as_ptr(),
-                    SH.as_ptr(),
-                    COMMAND.as_ptr(),
-                    command.command().as_ptr(),
-                    std::ptr::null() as *const c_char,
-                );
-                _exit(1);
+                close_pipes(&side_channels);
+                Err(ProcessError::CouldNotFork(errno))
             }
             pid => {
+                self.pid = pid;
+                self.script = pid;
+                self.running = true;
+                if let Some(forwarding) = forwarding {
+                    // Fails with EACCES if the child has already exec'd, by which point it has
+                    // done this itself.
+                    setpgid(pid, pid);
+                    forwarding.attach(pid);
+                    self.forwarding = Some(forwarding);
+                }
+                close(report_fds[1]);
                 close(in_fds[0]);
                 close(out_fds[1]);
                 close(err_fds[1]);
+                for pipe in &side_channels {
+                    close(pipe[1]);
+                }
+                let report = Self::read_report(report_fds[0]);
+                close(report_fds[0]);
+                if plan.supervised() {
+                    close(straggler_fds[1]);
+                    // The supervisor sends the script's pid before anything else.
+                    match Self::read_script_pid(straggler_fds[0]) {
+                        Some(script) if report.is_none() => self.script = script,
+                        _ => {
+                            close(straggler_fds[0]);
+                            straggler_fds[0] = -1;
+                        }
+                    }
+                }
+                if let Some((stage, errno)) = report {
+                    for fd in parent_ends {
+                        close(fd);
+                    }
+                    for pipe in &side_channels {
+                        close(pipe[0]);
+                    }
+                    self.running = false;
+                    Self::wait(pid)?;
+                    return Err(ProcessError::SpawnFailed(stage, errno));
+                }
+                if straggler_fds[0] != -1 {
+                    let mut stragglers = Reader::new();
+                    stragglers
+                        .read(straggler_fds[0])
+                        .map_err(|_| ProcessError::CouldNotGetStdout)?;
+                    self.stragglers = Some(stragglers);
+                }
+                for pipe in &side_channels {
+                    let mut side = Reader::new();
+                    side.read(pipe[0]).map_err(|_| ProcessError::CouldNotGetStdout)?;
+                    self.sides.push(side);
+                }
                 self.fds[0] = in_fds[1];
                 self.fds[1] = out_fds[0];
                 self.fds[2] = err_fds[0];
-                self.pid = pid;
-                self.stdout.read(self.fds[1]).map_err(|_| ProcessError::CouldNotGetStdout)?;
-                self.stderr.read(self.fds[2]).map_err(|_| ProcessError::CouldNotGetStderr)?;
+                if !self.streaming && self.fds[1] != -1 {
+                    self.stdout.read(self.fds[1]).map_err(|_| ProcessError::CouldNotGetStdout)?;
+                }
+                if !self.streaming && self.fds[2] != -1 {
+                    self.stderr.read(self.fds[2]).map_err(|_| ProcessError::CouldNotGetStderr)?;
+                }
                 Ok(())
             }
         }
     }
 
     pub(crate) unsafe fn close(&mut self) -> Result<c_int, ProcessError> {
-        close(self.fds[0]);
-        let mut status = -1;
-        waitpid(self.pid, &mut status, 0);
-        self.stdout.stop();
-        self.stderr.stop();
-        let stdout_result = self.stdout.join().map_err(|_| ProcessError::CouldNotGetStdout);
-        let stderr_result = self.stderr.join().map_err(|_| ProcessError::CouldNotGetStderr);
+        self.close_stdin();
+        // Nobody's left to read them, so don't let the child block writing to them.
+        self.close_streams();
+        let waited = Self::wait(self.pid);
+        self.finished = SystemTime::now();
+        self.running = false;
+        // Neither is being read if streaming, or if it doesn't come back to us.
+        let stdout_result = match self.stdout.reading() {
+            true => self.stdout.join().map_err(|_| ProcessError::CouldNotGetStdout),
+            false => Ok(()),
+        };
+        let stderr_result = match self.stderr.reading() {
+            true => self.stderr.join().map_err(|_| ProcessError::CouldNotGetStderr),
+            false => Ok(()),
+        };
+        if let Some(stragglers) = &mut self.stragglers {
+            // Nothing the script does can break this pipe, so there's nothing worth reporting.
+            let _ = stragglers.join();
+        }
+        for side in &mut self.sides {
+            // Whatever's missing from it is missing, however it went missing.
+            let _ = side.join();
+        }
+        let (status, usage) = waited?;
+        self.usage = usage.into();
+        if let Some(signal) = self.forwarding.take().and_then(|f| f.interrupted()) {
+            return Err(ProcessError::Interrupted(signal));
+        }
         return match WIFEXITED(status) {
             true => {
                 if stdout_result.is_err() {
@@ -205,22 +453,209 @@ impl Process {
                 }
                 Ok(WEXITSTATUS(status))
             }
-            false => Err(ProcessError::OpenDidNotCloseNormally),
+            false => Err(ProcessError::OpenDidNotCloseNormally(WTERMSIG(status))),
         };
     }
 
+    /// Send `signal` to the child, if it's still running. If the child is a supervisor, the
+    /// signal goes to the script, and the supervisor cleans up after it.
+    pub(crate) fn kill(&self, signal: c_int) -> Result<(), ProcessError> {
+        if !self.running {
+            return Ok(());
+        }
+        match unsafe { kill(self.script, signal) } {
+            -1 => Err(ProcessError::CouldNotKill(errno())),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn on_drop(mut self, on_drop: OnDrop) -> Self {
+        self.on_drop = on_drop;
+        self
+    }
+
+    /// Leave the child's stdout and stderr for the caller to read from [`Process::stdio`],
+    /// rather than reading them to EOF in the background.
+    pub(crate) fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    /// Give the child `fd` as its stdin, rather than a pipe for us to write to, e.g. the read
+    /// end of a pipe from another child. Takes ownership of `fd`, closing it once the child
+    /// has been forked.
+    pub(crate) fn stdin_from(mut self, fd: c_int) -> Self {
+        self.stdin_from = fd;
+        self
+    }
+
+    /// Give the child `fd` as its stdout, rather than a pipe for us to read, e.g. the write
+    /// end of a pipe to another child. Takes ownership of `fd`, closing it once the child has
+    /// been forked.
+    pub(crate) fn stdout_to(mut self, fd: c_int) -> Self {
+        self.stdout_to = fd;
+        self
+    }
+
+    pub(crate) fn stderr_to(mut self, stderr: Stderr) -> Self {
+        self.stderr_to = stderr;
+        self
+    }
+
+    /// Create a pipe for the script to report back through besides its stdio, returning the
+    /// fd it inherits the write end at. The other end is read like stdout. There can be two.
+    pub(crate) fn side_channel(&mut self) -> Result<c_int, ProcessError> {
+        let mut pipe: [c_int; 2] = [-1, -1];
+        unsafe {
+            self.pipe(&mut pipe, || {})?;
+            // Clear of the fds its stdio is about to be dup'd onto.
+            if pipe[1] < 3 {
+                let moved = fcntl(pipe[1], F_DUPFD_CLOEXEC, 3);
+                let errno = errno();
+                close(pipe[1]);
+                if moved == -1 {
+                    close(pipe[0]);
+                    return Err(ProcessError::CouldNotCreatePipe(errno));
+                }
+                pipe[1] = moved;
+            }
+        }
+        self.side_channels.push(pipe);
+        Ok(pipe[1])
+    }
+
+    /// The parent's ends of the child's stdin, stdout and stderr, once it has been opened.
+    /// Only stdin is ours to write to, and the others to read from, if
+    /// [`streaming`](Process::streaming).
+    pub(crate) fn stdio(&self) -> [c_int; 3] {
+        self.fds
+    }
+
+    unsafe fn close_streams(&mut self) {
+        if self.streaming {
+            for fd in &mut self.fds[1..] {
+                if *fd != -1 {
+                    close(*fd);
+                    *fd = -1;
+                }
+            }
+        }
+    }
+
+    unsafe fn close_stdin(&mut self) {
+        if self.fds[0] != -1 {
+            close(self.fds[0]);
+            self.fds[0] = -1;
+        }
+    }
+
     pub(crate) fn stdout(&self) -> Result<String, ProcessError> {
-        Ok(self.stdout.contents())
+        self.stdout.contents().map_err(|_| ProcessError::CouldNotGetStdout)
     }
 
     pub(crate) fn stderr(&self) -> Result<String, ProcessError> {
-        Ok(self.stderr.contents())
+        self.stderr.contents().map_err(|_| ProcessError::CouldNotGetStderr)
     }
 
-    unsafe fn dup(&self, fd: c_int) -> Result<(), ProcessError> {
-        match dup(fd) {
-            -1 => Err(ProcessError::CouldNotDupFd(fd)),
-            _ => Ok(()),
+    /// Everything that came down the `n`th [`side_channel`](Process::side_channel), counting
+    /// from 0, once the child has been closed, if it was given one.
+    pub(crate) fn side(&self, n: usize) -> Option<&[u8]> {
+        self.sides.get(n).map(Reader::bytes)
+    }
+
+    /// The processes the supervisor had to kill, once the child has been closed.
+    pub(crate) fn stragglers(&self) -> Vec<Straggler> {
+        self.records(SupervisorRecord::STRAGGLER)
+            .map(|(pid, _, name)| Straggler {
+                pid,
+                name,
+            })
+            .collect()
+    }
+
+    /// The pid and name of the first process killed for making a system call its seccomp
+    /// filter denied, and the call's number, once the child has been closed.
+    pub(crate) fn blocked_syscall(&self) -> Option<(c_int, c_int, String)> {
+        self.records(SupervisorRecord::BLOCKED_SYSCALL).next()
+    }
+
+    /// The pid, system call and name in each of the supervisor's records of `kind`.
+    fn records(&self, kind: c_int) -> impl Iterator<Item = (c_int, c_int, String)> + '_ {
+        let bytes = self.stragglers.as_ref().map_or(&[][..], Reader::bytes);
+        bytes.chunks_exact(size_of::<SupervisorRecord>()).filter_map(move |record| {
+            let int = |i: usize| {
+                let bytes = &record[i * size_of::<c_int>()..(i + 1) * size_of::<c_int>()];
+                c_int::from_ne_bytes(bytes.try_into().expect("Slice is a c_int."))
+            };
+            let comm = &record[3 * size_of::<c_int>()..];
+            let name = comm.split(|b| *b == 0).next().unwrap_or_default();
+            (int(0) == kind).then(|| (int(1), int(2), String::from_utf8_lossy(name).into_owned()))
+        })
+    }
+
+    /// Everything read from stdout and stderr so far, for reporting alongside an error.
+    pub(crate) fn partial_output(&self) -> (String, String) {
+        (self.stdout.partial(), self.stderr.partial())
+    }
+
+    /// What the child used, once it has been closed.
+    pub(crate) fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    /// When the child was forked.
+    pub(crate) fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// When the child was reaped, once it has been closed.
+    pub(crate) fn finished(&self) -> SystemTime {
+        self.finished
+    }
+
+    /// The child's pid, once it has been forked.
+    pub(crate) fn pid(&self) -> Option<c_int> {
+        (self.pid > 0).then_some(self.pid)
+    }
+
+    /// Reap the child, returning its status and the resources it (and any descendants it
+    /// reaped) used.
+    unsafe fn wait(pid: c_int) -> Result<(c_int, rusage), ProcessError> {
+        let mut status = -1;
+        let mut usage = MaybeUninit::<rusage>::zeroed().assume_init();
+        loop {
+            match wait4(pid, &mut status, 0, &mut usage) {
+                -1 => match errno() {
+                    EINTR => continue,
+                    errno => return Err(ProcessError::CouldNotWait(errno)),
+                },
+                _ => return Ok((status, usage)),
+            }
+        }
+    }
+
+    /// Block until the child has either exec'd, closing the report pipe, or written down it
+    /// which stage of its setup failed and with what errno.
+    unsafe fn read_report(fd: c_int) -> Option<(SpawnStage, c_int)> {
+        let mut report: SpawnReport = [-1, -1];
+        loop {
+            match read(fd, report.as_mut_ptr() as *mut c_void, size_of::<SpawnReport>()) {
+                -1 if errno() == EINTR => continue,
+                n if n as usize == size_of::<SpawnReport>() => break,
+                _ => return None,
+            }
+        }
+        Some((SpawnStage::from_raw(report[0])?, report[1]))
+    }
+
+    unsafe fn read_script_pid(fd: c_int) -> Option<c_int> {
+        let mut pid: c_int = -1;
+        loop {
+            match read(fd, &mut pid as *mut c_int as *mut c_void, size_of::<c_int>()) {
+                -1 if errno() == EINTR => continue,
+                n if n as usize == size_of::<c_int>() => return Some(pid),
+                _ => return None,
+            }
         }
     }
 
@@ -229,21 +664,96 @@ impl Process {
         fds: &mut [c_int; 2],
         on_error: impl FnOnce() -> (),
     ) -> Result<(), ProcessError> {
-        match pipe(fds.as_mut_ptr()) {
+        // O_CLOEXEC, so a sibling spawned concurrently from another thread can't inherit
+        // our ends and keep them open after we've closed them.
+        match pipe2(fds.as_mut_ptr(), O_CLOEXEC) {
             -1 => {
+                let errno = errno();
                 on_error();
-                Err(ProcessError::CouldNotCreatePipe)
+                Err(ProcessError::CouldNotCreatePipe(errno))
             }
             _ => Ok(()),
         }
     }
 }
 
+impl Drop for Process {
+    /// Clean up a child which was opened but never closed, e.g. because we panicked in between.
+    fn drop(&mut self) {
+        // Given to us for a child which never got as far as being opened.
+        let side_channels = self.side_channels.iter().flatten().copied();
+        for fd in [self.stdin_from, self.stdout_to].into_iter().chain(side_channels) {
+            if fd != -1 {
+                unsafe { close(fd) };
+            }
+        }
+        if !self.running {
+            return;
+        }
+        unsafe {
+            self.close_stdin();
+            self.close_streams();
+            // Nobody's going to ask what came down it.
+            for side in &mut self.sides {
+                side.abort();
+            }
+            match self.on_drop {
+                OnDrop::Kill => {
+                    kill(self.script, SIGKILL);
+                    let _ = Self::wait(self.pid);
+                    self.stdout.abort();
+                    self.stderr.abort();
+                    let _ = self.stdout.join();
+                    let _ = self.stderr.join();
+                    if let Some(stragglers) = &mut self.stragglers {
+                        let _ = stragglers.join();
+                    }
+                }
+                OnDrop::Wait => {
+                    let _ = Self::wait(self.pid);
+                    let _ = self.stdout.join();
+                    let _ = self.stderr.join();
+                    if let Some(stragglers) = &mut self.stragglers {
+                        let _ = stragglers.join();
+                    }
+                }
+                OnDrop::Detach => {
+                    let pid = self.pid;
+                    let mut stdout = std::mem::replace(&mut self.stdout, Reader::new());
+                    let mut stderr = std::mem::replace(&mut self.stderr, Reader::new());
+                    let mut stragglers = self.stragglers.take();
+                    std::thread::spawn(move || {
+                        let _ = Self::wait(pid);
+                        let _ = stdout.join();
+                        let _ = stderr.join();
+                        if let Some(stragglers) = &mut stragglers {
+                            let _ = stragglers.join();
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Read errno straight away, before anything else (even a `close`) gets the chance to clobber it.
+fn errno() -> c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
     use rand::distributions::{Alphanumeric, DistString};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
 
-    use super::{BashCommand, Process};
+    use super::Process;
+    use crate::command::BashCommand;
 
     #[test]
     fn test_process_with_no_output() -> anyhow::Result<()> {
@@ -460,6 +970,112 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_process_from_many_threads_while_allocating() -> anyhow::Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let allocators = (0..4)
+            .map(|_| {
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let v: Vec<String> = (0..256).map(|i| i.to_string()).collect();
+                        drop(v);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let spawners = (0..4)
+            .map(|i| {
+                std::thread::spawn(move || -> anyhow::Result<()> {
+                    for j in 0..25 {
+                        let mut process = Process::new();
+                        let command = BashCommand::new(format!("echo -n {i}-{j}"))?;
+                        unsafe {
+                            process.open(command)?;
+                            assert_eq!(process.close()?, 0);
+                        }
+                        assert_eq!(process.stdout()?, format!("{i}-{j}"));
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for spawner in spawners {
+            spawner.join().unwrap()?;
+        }
+        stop.store(true, Ordering::Relaxed);
+        for allocator in allocators {
+            allocator.join().unwrap();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_does_not_inherit_non_standard_fds() -> anyhow::Result<()> {
+        let mut leaky: [libc::c_int; 2] = [-1, -1];
+        assert_eq!(unsafe { libc::pipe(leaky.as_mut_ptr()) }, 0);
+
+        let mut process = Process::new();
+        let command = BashCommand::new("ls /proc/self/fd")?;
+        unsafe {
+            process.open(command)?;
+            assert_eq!(process.close()?, 0);
+            libc::close(leaky[0]);
+            libc::close(leaky[1]);
+        }
+        // 3 is the fd `ls` itself opens on /proc/self/fd.
+        Ok(assert_eq!(process.stdout()?, "0\n1\n2\n3\n"))
+    }
+
+    #[test]
+    fn test_concurrent_processes_dont_hold_each_others_pipes_open() -> anyhow::Result<()> {
+        let sleepers = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || -> anyhow::Result<()> {
+                    let mut processes = Vec::new();
+                    for _ in 0..25 {
+                        let mut process = Process::new();
+                        unsafe { process.open(BashCommand::new("sleep 3")?)? };
+                        processes.push(process);
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    for mut process in processes {
+                        assert_eq!(unsafe { process.close()? }, 0);
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let quick = (0..4)
+            .map(|i| {
+                std::thread::spawn(move || -> anyhow::Result<()> {
+                    for j in 0..50 {
+                        let start = Instant::now();
+                        let mut process = Process::new();
+                        unsafe {
+                            process.open(BashCommand::new(format!("cat; echo -n {i}-{j}"))?)?;
+                            assert_eq!(process.close()?, 0);
+                        }
+                        assert_eq!(process.stdout()?, format!("{i}-{j}"));
+                        assert!(
+                            start.elapsed() < Duration::from_secs(2),
+                            "a quick command was held open by a sibling's child"
+                        );
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in quick.into_iter().chain(sleepers) {
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    }
+
     const BACKGROUND: &'static str = r#"
          #!/usr/bin/env bash
         set -euf -o pipefail
@@ -0,0 +1,380 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{command::Command, error::RashError, output::Output};
+
+/// When and how often to run a [`Command`] again after it fails, as given to
+/// [`Command::retry`].
+///
+/// By default, an attempt is retried if it returned an error or anything but 0, straight
+/// away. Say which failures are worth retrying with [`Retry::on_exit_codes`],
+/// [`Retry::on_stderr`] and [`Retry::when`], and how long to wait in between with
+/// [`Retry::fixed`] or [`Retry::exponential`].
+///
+/// ```
+/// use std::time::Duration;
+/// use rsbash::{Command, RashError, Retry};
+///
+/// pub fn flaky() -> Result<(), RashError> {
+///     let dir = tempfile::TempDir::new().unwrap();
+///     let retry = Retry::new(3)
+///         .exponential(Duration::from_millis(10), Duration::from_secs(1))
+///         .jitter(true)
+///         .on_stderr("try again");
+///     let script = "[ -e attempted ] || { touch attempted; echo try again >&2; exit 1; }";
+///     let retried = Command::new(script)
+///         .current_dir(dir.path())
+///         .retry(retry)
+///         .retried();
+///     assert_eq!(retried.attempts.len(), 2);
+///     assert_eq!(retried.attempts[0].result.as_ref().unwrap().stderr, "try again\n");
+///     assert_eq!(retried.into_result()?.ret_val, 0);
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Retry {
+    max_attempts: usize,
+    backoff: Backoff,
+    jitter: bool,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Backoff {
+    Fixed(Duration),
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+#[derive(Clone)]
+enum Condition {
+    ExitCodes(Vec<i32>),
+    Stderr(String),
+    When(Arc<RetryFn>),
+}
+
+/// A predicate given to [`Retry::when`].
+type RetryFn = dyn Fn(&Result<Output, RashError>) -> bool + Send + Sync;
+
+impl fmt::Debug for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let conditions: Vec<_> = self
+            .conditions
+            .iter()
+            .map(|condition| match condition {
+                Condition::ExitCodes(codes) => format!("ExitCodes({codes:?})"),
+                Condition::Stderr(pattern) => format!("Stderr({pattern:?})"),
+                Condition::When(_) => "When".to_string(),
+            })
+            .collect();
+        f.debug_struct("Retry")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("jitter", &self.jitter)
+            .field("conditions", &conditions)
+            .finish()
+    }
+}
+
+impl Retry {
+    /// Run the command up to `max_attempts` times in all, including the first.
+    pub fn new(max_attempts: usize) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff: Backoff::Fixed(Duration::ZERO),
+            jitter: false,
+            conditions: Vec::new(),
+        }
+    }
+
+    /// Wait `delay` before each retry.
+    pub fn fixed(mut self, delay: Duration) -> Self {
+        self.backoff = Backoff::Fixed(delay);
+        self
+    }
+
+    /// Wait `initial` before the first retry, doubling the wait before each one after that, up
+    /// to `max`.
+    pub fn exponential(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Backoff::Exponential {
+            initial,
+            max,
+        };
+        self
+    }
+
+    /// Wait a random amount between half and all of each delay, so that lots of commands
+    /// failing at once don't all retry at once. Defaults to `false`.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Retry an attempt which returned one of `codes`.
+    ///
+    /// The errors standing in for a return value count as the one bash would give:
+    /// [`RashError::CommandNotExecutable`] as 126, [`RashError::CommandNotFound`] as 127, and
+    /// [`RashError::KilledBySignal`] as 128 plus the signal. Other errors never match.
+    pub fn on_exit_codes<I: IntoIterator<Item = i32>>(mut self, codes: I) -> Self {
+        self.conditions.push(Condition::ExitCodes(codes.into_iter().collect()));
+        self
+    }
+
+    /// Retry an attempt which failed, by returning an error or anything but 0, with `pattern`
+    /// somewhere in its stderr.
+    pub fn on_stderr<S: AsRef<str>>(mut self, pattern: S) -> Self {
+        self.conditions.push(Condition::Stderr(pattern.as_ref().to_string()));
+        self
+    }
+
+    /// Retry an attempt for which `retry` returns `true`, whether it failed or not.
+    pub fn when<F>(mut self, retry: F) -> Self
+    where
+        F: Fn(&Result<Output, RashError>) -> bool + Send + Sync + 'static,
+    {
+        self.conditions.push(Condition::When(Arc::new(retry)));
+        self
+    }
+
+    /// Run `command` until an attempt isn't worth retrying, or there have been
+    /// `max_attempts`.
+    pub(crate) fn run(&self, command: &Command) -> Retried {
+        let mut attempts: Vec<Attempt> = Vec::with_capacity(self.max_attempts);
+        loop {
+            if let Some(delay) = attempts.len().checked_sub(1).map(|retries| self.delay(retries)) {
+                std::thread::sleep(delay);
+            }
+            let start = Instant::now();
+            let result = command.spawn().and_then(|child| child.wait());
+            let elapsed = start.elapsed();
+            let again = attempts.len() + 1 < self.max_attempts && self.should_retry(&result);
+            attempts.push(Attempt {
+                result,
+                elapsed,
+            });
+            if !again {
+                return Retried {
+                    attempts,
+                };
+            }
+        }
+    }
+
+    /// Whether an attempt which had `result` should be retried: if it failed, and matches one
+    /// of the conditions given, or any failure if none were.
+    fn should_retry(&self, result: &Result<Output, RashError>) -> bool {
+        let failed = result.as_ref().map_or(true, |output| output.ret_val != 0);
+        if self.conditions.is_empty() {
+            return failed;
+        }
+        self.conditions.iter().any(|condition| match condition {
+            Condition::ExitCodes(codes) => match result {
+                Ok(Output {
+                    ret_val,
+                    ..
+                })
+                | Err(RashError::ScriptFailed {
+                    ret_val,
+                    ..
+                }) => codes.contains(ret_val),
+                Err(RashError::CommandNotExecutable {
+                    ..
+                }) => codes.contains(&126),
+                Err(RashError::CommandNotFound {
+                    ..
+                }) => codes.contains(&127),
+                Err(RashError::KilledBySignal {
+                    signal,
+                    ..
+                }) => codes.contains(&(128 + signal)),
+                Err(_) => false,
+            },
+            Condition::Stderr(pattern) => {
+                let stderr = match result {
+                    Ok(output) => &output.stderr,
+                    Err(e) => e.stderr(),
+                };
+                failed && stderr.contains(pattern.as_str())
+            }
+            Condition::When(retry) => retry(result),
+        })
+    }
+
+    /// How long to wait before retry number `retry`, counting from 0.
+    fn delay(&self, retry: usize) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential {
+                initial,
+                max,
+            } => initial.saturating_mul(1 << retry.min(31)).min(max),
+        };
+        match self.jitter {
+            true => delay.mul_f64(1.0 - Self::random() / 2.0),
+            false => delay,
+        }
+    }
+
+    /// A number between 0 and 1.
+    fn random() -> f64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::UNIX_EPOCH.elapsed().unwrap_or_default().as_nanos());
+        (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// One run of a [`Command`] with a [`Retry`] policy.
+#[derive(Debug)]
+pub struct Attempt {
+    /// What the command returned.
+    pub result: Result<Output, RashError>,
+    /// How long it took.
+    pub elapsed: Duration,
+}
+
+/// Every attempt at running a [`Command`] with a [`Retry`] policy, as returned by
+/// [`Command::retried`].
+#[derive(Debug)]
+pub struct Retried {
+    /// Each attempt, in the order they were made. There's always at least one.
+    pub attempts: Vec<Attempt>,
+}
+
+impl Retried {
+    /// The result of the last attempt.
+    pub fn result(&self) -> Result<&Output, &RashError> {
+        self.last().result.as_ref()
+    }
+
+    /// The result of the last attempt.
+    pub fn into_result(mut self) -> Result<Output, RashError> {
+        self.attempts.pop().expect("There's always at least one attempt.").result
+    }
+
+    fn last(&self) -> &Attempt {
+        self.attempts.last().expect("There's always at least one attempt.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Retry;
+    use crate::{Command, RashError};
+
+    /// A script which fails with `code` and `stderr` until it has been run `failures` times.
+    fn flaky(dir: &tempfile::TempDir, failures: usize, code: i32, stderr: &str) -> Command {
+        let script = format!(
+            "echo >> attempts; [ $(wc -l < attempts) -gt {failures} ] || {{ echo {stderr} >&2; exit {code}; }}"
+        );
+        Command::new(script).current_dir(dir.path())
+    }
+
+    #[test]
+    fn test_retry_until_success() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let retried = flaky(&dir, 2, 1, "oops").retry(Retry::new(5)).retried();
+        let codes: Vec<_> =
+            retried.attempts.iter().map(|a| a.result.as_ref().unwrap().ret_val).collect();
+        assert_eq!(codes, [1, 1, 0]);
+        Ok(assert_eq!(retried.result().unwrap().ret_val, 0))
+    }
+
+    #[test]
+    fn test_retry_gives_up() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let command = flaky(&dir, 10, 3, "oops").retry(Retry::new(3));
+        assert_eq!(command.output()?.ret_val, 3);
+        Ok(assert_eq!(std::fs::read_to_string(dir.path().join("attempts"))?.len(), 3))
+    }
+
+    #[test]
+    fn test_retry_only_on_exit_codes() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let retry = Retry::new(5).on_exit_codes([75]);
+        assert_eq!(flaky(&dir, 1, 75, "oops").retry(retry.clone()).retried().attempts.len(), 2);
+        let dir = tempfile::TempDir::new()?;
+        Ok(assert_eq!(flaky(&dir, 1, 1, "oops").retry(retry).retried().attempts.len(), 1))
+    }
+
+    #[test]
+    fn test_retry_on_exit_codes_in_strict_mode() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let script = "echo >> attempts\n[ $(wc -l < attempts) -gt 1 ] || (exit 75)";
+        let command = Command::new(script).current_dir(dir.path()).strict(true);
+        let retried = command.retry(Retry::new(5).on_exit_codes([75])).retried();
+        assert!(matches!(retried.attempts[0].result, Err(RashError::ScriptFailed { .. })));
+        Ok(assert_eq!(retried.into_result()?.ret_val, 0))
+    }
+
+    #[test]
+    fn test_retry_only_on_stderr() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let retry = Retry::new(5).on_stderr("Connection refused");
+        let command = flaky(&dir, 1, 7, "curl: Connection refused").retry(retry.clone());
+        assert_eq!(command.retried().attempts.len(), 2);
+        let dir = tempfile::TempDir::new()?;
+        let command = flaky(&dir, 1, 7, "curl: 404").retry(retry);
+        Ok(assert_eq!(command.retried().attempts.len(), 1))
+    }
+
+    #[test]
+    fn test_retry_on_errors() {
+        let command = Command::new("i_am_not_a_valid_executable").retry(Retry::new(2));
+        let retried = command.retried();
+        assert_eq!(retried.attempts.len(), 2);
+        assert!(matches!(retried.into_result(), Err(RashError::CommandNotFound { .. })));
+    }
+
+    #[test]
+    fn test_retry_on_exit_codes_of_errors() {
+        let retry = Retry::new(3).on_exit_codes([126, 127, 137]);
+        for script in ["i_am_not_a_valid_executable", "/dev/null", "kill -9 $$"] {
+            let retried = Command::new(script).retry(retry.clone()).retried();
+            assert_eq!(retried.attempts.len(), 3, "{script}");
+        }
+        let retried = Command::new("kill -9 $$").retry(Retry::new(3).on_exit_codes([127]));
+        assert_eq!(retried.retried().attempts.len(), 1);
+    }
+
+    #[test]
+    fn test_retry_when() -> Result<(), RashError> {
+        let retry = Retry::new(3).when(|result| result.as_ref().is_ok_and(|o| o.stdout.is_empty()));
+        let retried = Command::new("true").retry(retry).retried();
+        Ok(assert_eq!(retried.attempts.len(), 3))
+    }
+
+    #[test]
+    fn test_retry_backoff() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let retry =
+            Retry::new(4).exponential(Duration::from_millis(50), Duration::from_millis(100));
+        let start = Instant::now();
+        let retried = flaky(&dir, 3, 1, "oops").retry(retry).retried();
+        // 50ms, then 100ms, then 100ms again.
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(retried.attempts.len(), 4);
+        Ok(assert!(retried.attempts.iter().all(|attempt| attempt.elapsed < start.elapsed())))
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = Retry::new(10).exponential(Duration::from_secs(1), Duration::from_secs(60));
+        let delays: Vec<_> = (0..8).map(|n| retry.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        let retry = retry.jitter(true);
+        assert!((0..100)
+            .map(|_| retry.delay(3))
+            .all(|delay| { delay >= Duration::from_secs(4) && delay <= Duration::from_secs(8) }));
+        assert_eq!(Retry::new(2).fixed(Duration::from_secs(3)).delay(5), Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,312 @@
+use libc::{
+    c_char, c_int, c_ulong, c_void, close, getgid, getuid, ifreq, ioctl, mkdir, mount, open,
+    socket, statvfs, unshare, write, AF_INET, CLONE_NEWIPC, CLONE_NEWNET, CLONE_NEWNS,
+    CLONE_NEWPID, CLONE_NEWUSER, CLONE_NEWUTS, IFF_UP, MS_BIND, MS_NODEV, MS_NOEXEC, MS_NOSUID,
+    MS_PRIVATE, MS_RDONLY, MS_REC, MS_REMOUNT, O_CLOEXEC, O_CREAT, O_PATH, O_WRONLY, SIOCGIFFLAGS,
+    SIOCSIFFLAGS, SOCK_CLOEXEC, SOCK_DGRAM, ST_NOATIME, ST_NODEV, ST_NODIRATIME, ST_NOEXEC,
+    ST_NOSUID, ST_RELATIME,
+};
+use std::{
+    cell::Cell,
+    ffi::{CString, NulError},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{child::numbered_path, error::SpawnStage};
+
+/// Isolation for a [`Command`](crate::Command), set with
+/// [`Command::sandbox`](crate::Command::sandbox).
+///
+/// The script is run in new user, mount, pid, network, IPC and UTS namespaces. It runs as root
+/// within them, mapped to the caller's own uid and gid, so this works unprivileged wherever
+/// user namespaces are enabled. Inside, the script:
+///
+/// - is pid 1, and sees only its own descendants in `/proc`; when it exits, they're killed,
+/// - has only a loopback network interface,
+/// - has an empty, private `/tmp`,
+/// - sees the rest of the host's filesystem as usual, bar any paths made
+///   [read-only](Sandbox::read_only).
+///
+/// ```
+/// use rsbash::{Command, RashError, Sandbox};
+///
+/// pub fn sandboxed() -> Result<(), RashError> {
+///     let output = Command::new("echo $$; ls -A /tmp").sandbox(Sandbox::new()).output()?;
+///     assert_eq!(output.stdout, "1\n");
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    read_only: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// A sandbox with the host's filesystem mounted as it is.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `path` read-only within the sandbox. Paths under `/tmp` are carried over into the
+    /// private `/tmp`, read-only.
+    pub fn read_only<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_only.push(path.as_ref().to_path_buf());
+        self
+    }
+}
+
+/// A path to bind read-only into the sandbox, with everything the child needs to do so.
+struct ReadOnly {
+    path: CString,
+    /// Every proper ancestor of `path`, shortest first, in case it has to be recreated
+    /// within the private `/tmp`.
+    ancestors: Vec<CString>,
+    dir: bool,
+    /// An `O_PATH` fd for `path`, opened before `/tmp` is mounted over.
+    fd: Cell<c_int>,
+}
+
+/// Everything the forked child needs to set up a [`Sandbox`] without allocating.
+pub(crate) struct SandboxPlan {
+    uid_map: CString,
+    gid_map: CString,
+    read_only: Vec<ReadOnly>,
+}
+
+lazy_static! {
+    static ref SETGROUPS: CString = CString::new("/proc/self/setgroups").expect("CString failed.");
+    static ref UID_MAP: CString = CString::new("/proc/self/uid_map").expect("CString failed.");
+    static ref GID_MAP: CString = CString::new("/proc/self/gid_map").expect("CString failed.");
+    static ref DENY: CString = CString::new("deny").expect("CString failed.");
+    static ref ROOT: CString = CString::new("/").expect("CString failed.");
+    static ref TMP: CString = CString::new("/tmp").expect("CString failed.");
+    static ref TMPFS: CString = CString::new("tmpfs").expect("CString failed.");
+    static ref TMP_MODE: CString = CString::new("mode=1777").expect("CString failed.");
+    static ref PROC: CString = CString::new("/proc").expect("CString failed.");
+    static ref PROCFS: CString = CString::new("proc").expect("CString failed.");
+}
+
+impl SandboxPlan {
+    pub(crate) fn new(sandbox: &Sandbox) -> Result<Self, NulError> {
+        // Make sure the lazy statics are built here, rather than in the child.
+        lazy_static::initialize(&SETGROUPS);
+        lazy_static::initialize(&UID_MAP);
+        lazy_static::initialize(&GID_MAP);
+        lazy_static::initialize(&DENY);
+        lazy_static::initialize(&ROOT);
+        lazy_static::initialize(&TMP);
+        lazy_static::initialize(&TMPFS);
+        lazy_static::initialize(&TMP_MODE);
+        lazy_static::initialize(&PROC);
+        lazy_static::initialize(&PROCFS);
+
+        let (uid, gid) = unsafe { (getuid(), getgid()) };
+        let read_only = sandbox
+            .read_only
+            .iter()
+            .map(|path| {
+                let ancestors = path
+                    .ancestors()
+                    .skip(1)
+                    .filter(|a| a.parent().is_some())
+                    .map(|a| CString::new(a.as_os_str().as_bytes()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ReadOnly {
+                    path: CString::new(path.as_os_str().as_bytes())?,
+                    ancestors: ancestors.into_iter().rev().collect(),
+                    dir: path.is_dir(),
+                    fd: Cell::new(-1),
+                })
+            })
+            .collect::<Result<Vec<_>, NulError>>()?;
+        Ok(Self {
+            uid_map: CString::new(format!("0 {uid} 1"))?,
+            gid_map: CString::new(format!("0 {gid} 1"))?,
+            read_only,
+        })
+    }
+
+    /// Move into the new namespaces, and set up the filesystem and network within them.
+    /// Only ever call this in the child, straight after `fork()`.
+    ///
+    /// On failure, returns the stage which failed, with errno left as it set it.
+    pub(crate) unsafe fn enter(&self) -> Result<(), SpawnStage> {
+        let namespaces =
+            CLONE_NEWUSER | CLONE_NEWNS | CLONE_NEWPID | CLONE_NEWNET | CLONE_NEWIPC | CLONE_NEWUTS;
+        if unshare(namespaces) == -1 {
+            return Err(SpawnStage::Unshare);
+        }
+        // An unprivileged process has to give up setgroups(2) before it can map its gid.
+        for (path, contents) in
+            [(&*SETGROUPS, &*DENY), (&*UID_MAP, &self.uid_map), (&*GID_MAP, &self.gid_map)]
+        {
+            if !Self::write_file(path, contents) {
+                return Err(SpawnStage::IdMap);
+            }
+        }
+
+        // Keep our mounts from propagating back out to the host.
+        let null = std::ptr::null::<c_char>();
+        if mount(null, ROOT.as_ptr(), null, MS_REC | MS_PRIVATE, std::ptr::null()) == -1 {
+            return Err(SpawnStage::Mount);
+        }
+        for read_only in &self.read_only {
+            match open(read_only.path.as_ptr(), O_PATH | O_CLOEXEC) {
+                -1 => return Err(SpawnStage::Mount),
+                fd => read_only.fd.set(fd),
+            }
+        }
+        let flags = MS_NOSUID | MS_NODEV;
+        let mode = TMP_MODE.as_ptr() as *const c_void;
+        if mount(TMPFS.as_ptr(), TMP.as_ptr(), TMPFS.as_ptr(), flags, mode) == -1 {
+            return Err(SpawnStage::Mount);
+        }
+        for read_only in &self.read_only {
+            read_only.bind()?;
+        }
+
+        Self::loopback_up()
+    }
+
+    /// Mount a `/proc` for the new pid namespace. This has to be done from within it, so by
+    /// the script's process rather than whichever called [`enter`](SandboxPlan::enter).
+    pub(crate) unsafe fn mount_proc(&self) -> Result<(), SpawnStage> {
+        let flags = MS_NOSUID | MS_NODEV | MS_NOEXEC;
+        match mount(PROCFS.as_ptr(), PROC.as_ptr(), PROCFS.as_ptr(), flags, std::ptr::null()) {
+            -1 => Err(SpawnStage::Mount),
+            _ => Ok(()),
+        }
+    }
+
+    unsafe fn write_file(path: &CString, contents: &CString) -> bool {
+        let fd = open(path.as_ptr(), O_WRONLY | O_CLOEXEC);
+        if fd == -1 {
+            return false;
+        }
+        let len = contents.as_bytes().len();
+        let written = write(fd, contents.as_ptr() as *const c_void, len);
+        close(fd);
+        written == len as isize
+    }
+
+    unsafe fn loopback_up() -> Result<(), SpawnStage> {
+        let fd = socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0);
+        if fd == -1 {
+            return Err(SpawnStage::Loopback);
+        }
+        let mut request = MaybeUninit::<ifreq>::zeroed().assume_init();
+        for (i, b) in b"lo".iter().enumerate() {
+            request.ifr_name[i] = *b as c_char;
+        }
+        if ioctl(fd, SIOCGIFFLAGS, &mut request) == -1 {
+            return Err(SpawnStage::Loopback);
+        }
+        request.ifr_ifru.ifru_flags |= IFF_UP as i16;
+        if ioctl(fd, SIOCSIFFLAGS, &request) == -1 {
+            return Err(SpawnStage::Loopback);
+        }
+        close(fd);
+        Ok(())
+    }
+}
+
+impl ReadOnly {
+    /// Bind the path over itself, then remount it read-only.
+    unsafe fn bind(&self) -> Result<(), SpawnStage> {
+        // If the path was under /tmp, it has to be recreated in the tmpfs first. Anywhere else,
+        // these all already exist, and fail harmlessly.
+        for ancestor in &self.ancestors {
+            mkdir(ancestor.as_ptr(), 0o755);
+        }
+        match self.dir {
+            true => {
+                mkdir(self.path.as_ptr(), 0o755);
+            }
+            false => {
+                let fd = open(self.path.as_ptr(), O_WRONLY | O_CREAT | O_CLOEXEC, 0o644);
+                if fd != -1 {
+                    close(fd);
+                }
+            }
+        }
+
+        let source = numbered_path(b"/proc/self/fd/", self.fd.get(), b"");
+        let source = source.as_ptr() as *const c_char;
+        let null = std::ptr::null::<c_char>();
+        if mount(source, self.path.as_ptr(), null, MS_BIND | MS_REC, std::ptr::null()) == -1 {
+            return Err(SpawnStage::Mount);
+        }
+        close(self.fd.get());
+
+        // Within a user namespace, a remount has to keep the flags the mount already had.
+        let mut stat = MaybeUninit::<statvfs>::zeroed().assume_init();
+        if statvfs(self.path.as_ptr(), &mut stat) == -1 {
+            return Err(SpawnStage::Mount);
+        }
+        let locked = ST_NOSUID | ST_NODEV | ST_NOEXEC | ST_NOATIME | ST_NODIRATIME | ST_RELATIME;
+        let flags = MS_BIND | MS_REMOUNT | MS_RDONLY | (stat.f_flag & locked) as c_ulong;
+        match mount(null, self.path.as_ptr(), null, flags, std::ptr::null()) {
+            -1 => Err(SpawnStage::Mount),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Sandbox;
+    use crate::{Command, RashError};
+
+    #[test]
+    fn test_sandbox_runs_as_root_and_pid_1() -> Result<(), RashError> {
+        let output =
+            Command::new("echo $$; id -u; echo /proc/[0-9]*").sandbox(Sandbox::new()).output()?;
+        Ok(assert_eq!(output.stdout, "1\n0\n/proc/1\n"))
+    }
+
+    #[test]
+    fn test_sandbox_only_has_loopback() -> Result<(), RashError> {
+        let output = Command::new(
+            "tail -n+3 /proc/net/dev | cut -d: -f1 | tr -d ' '; echo > /dev/tcp/127.0.0.1/1",
+        )
+        .sandbox(Sandbox::new())
+        .output()?;
+        assert_eq!(output.stdout, "lo\n");
+        // Refused rather than unreachable, so lo is up.
+        Ok(assert!(output.stderr.contains("Connection refused"), "{}", output.stderr))
+    }
+
+    #[test]
+    fn test_sandbox_has_a_private_tmp() -> anyhow::Result<()> {
+        let host = tempfile::tempdir()?;
+        let command = format!("ls -A /tmp; test -e {} || echo hidden", host.path().display());
+        let output = Command::new(command).sandbox(Sandbox::new()).output()?;
+        Ok(assert_eq!(output.stdout, "hidden\n"))
+    }
+
+    #[test]
+    fn test_sandbox_read_only_paths() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("file"), "contents")?;
+        let output = Command::new("cat file; touch new")
+            .current_dir(dir.path())
+            .sandbox(Sandbox::new().read_only(dir.path()))
+            .output()?;
+        assert_eq!(output.stdout, "contents");
+        assert!(output.stderr.contains("Read-only file system"), "{}", output.stderr);
+        Ok(assert!(!dir.path().join("new").exists()))
+    }
+
+    #[test]
+    fn test_sandbox_kills_background_jobs() -> Result<(), RashError> {
+        let start = Instant::now();
+        let output =
+            Command::new("sleep infinity & echo -n hi").sandbox(Sandbox::new()).output()?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(assert_eq!(output.stdout, "hi"))
+    }
+}
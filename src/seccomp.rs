@@ -0,0 +1,449 @@
+use libc::{
+    c_int, c_long, c_uint, c_void, close, cmsghdr, ioctl, iovec, kill, msghdr, prctl, recvmsg,
+    sendmsg, sock_filter, sock_fprog, syscall, SYS_seccomp, AF_UNIX, CMSG_DATA, CMSG_FIRSTHDR,
+    CMSG_LEN, CMSG_SPACE, EINTR, ENOSYS, EPERM, MSG_CMSG_CLOEXEC, PR_SET_NO_NEW_PRIVS, SCM_RIGHTS,
+    SIGSYS, SOL_SOCKET,
+};
+use std::mem::{size_of, size_of_val, MaybeUninit};
+
+use crate::error::SpawnStage;
+
+const SECCOMP_SET_MODE_FILTER: c_uint = 1;
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: c_uint = 1 << 3;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// `_IOWR('!', 0, struct seccomp_notif)` and `_IOWR('!', 1, struct seccomp_notif_resp)`.
+const SECCOMP_IOCTL_NOTIF_RECV: u64 = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: u64 = 0xc018_2101;
+
+/// Room for the control message carrying the listener's fd, in words, so that the
+/// `cmsghdr` at its start is aligned.
+const CONTROL_WORDS: usize =
+    unsafe { CMSG_SPACE(size_of::<c_int>() as c_uint) } as usize / size_of::<u64>();
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets into `struct seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+/// The low half of the first argument: the filter only runs on little endian architectures.
+const DATA_ARG0: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// On x86_64, the x32 ABI's system calls have this bit set, and would get around a filter
+/// written in terms of the 64-bit numbers.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The system calls the script needs between installing the filter and exec'ing bash, or
+/// to die if it can't: these can never be denied.
+const ALWAYS_ALLOWED: [c_long; 6] = [
+    libc::SYS_execve,
+    libc::SYS_sendmsg,
+    libc::SYS_close,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigreturn,
+];
+
+#[repr(C)]
+struct SeccompNotif {
+    id: u64,
+    pid: u32,
+    flags: u32,
+    data: libc::seccomp_data,
+}
+
+#[repr(C)]
+struct SeccompNotifResp {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+/// A seccomp-BPF filter for a [`Command`](crate::Command) (see seccomp(2)), set with
+/// [`Command::seccomp`](crate::Command::seccomp), which stops it making particular system
+/// calls.
+///
+/// Start from either [`allow_all`](Seccomp::allow_all) or [`deny_all`](Seccomp::deny_all),
+/// then make exceptions with [`allow`](Seccomp::allow) and [`deny`](Seccomp::deny), which
+/// take system call numbers such as `libc::SYS_ptrace`. Or start from one of the presets,
+/// [`no_network`](Seccomp::no_network) and [`no_admin`](Seccomp::no_admin).
+///
+/// Any process which makes a denied system call is killed with `SIGSYS`, and the command
+/// fails with [`RashError::SyscallBlocked`](crate::RashError::SyscallBlocked), naming the
+/// call. `PR_SET_NO_NEW_PRIVS` is set first, so nothing the command runs can gain privileges
+/// by exec'ing a setuid binary either.
+///
+/// The command runs under a supervisor, as with
+/// [`Command::reap_orphans`](crate::Command::reap_orphans), which watches for violations.
+/// Filters are only supported on x86_64 and aarch64: elsewhere, the command fails to spawn
+/// with [`SpawnStage::Seccomp`] and `ENOSYS`.
+///
+/// ```
+/// use rsbash::{Command, RashError, Seccomp};
+///
+/// pub fn offline() -> Result<(), RashError> {
+///     let command = Command::new("echo hi > /dev/tcp/127.0.0.1/80").seccomp(Seccomp::no_network());
+///     match command.output() {
+///         Err(RashError::SyscallBlocked { syscall, .. }) => assert_eq!(syscall, libc::SYS_socket),
+///         _ => unreachable!(),
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Seccomp {
+    default_allow: bool,
+    rules: Vec<(c_long, bool)>,
+    deny_network: bool,
+}
+
+impl Seccomp {
+    /// A filter which allows every system call, bar those [denied](Seccomp::deny).
+    pub fn allow_all() -> Self {
+        Self {
+            default_allow: true,
+            rules: Vec::new(),
+            deny_network: false,
+        }
+    }
+
+    /// A filter which denies every system call, bar those [allowed](Seccomp::allow), and the
+    /// few which can't be [denied](Seccomp::deny). bash itself needs a good few, so they'll
+    /// all need allowing.
+    pub fn deny_all() -> Self {
+        Self {
+            default_allow: false,
+            ..Self::allow_all()
+        }
+    }
+
+    /// Allow the system call numbered `syscall`. The first rule for a call wins.
+    pub fn allow(mut self, syscall: c_long) -> Self {
+        self.rules.push((syscall, true));
+        self
+    }
+
+    /// Deny the system call numbered `syscall`. The first rule for a call wins.
+    ///
+    /// `execve`, `sendmsg`, `close`, `exit`, `exit_group` and `rt_sigreturn` are always
+    /// allowed, as the script needs them between installing the filter and exec'ing bash, so
+    /// denying them has no effect.
+    pub fn deny(mut self, syscall: c_long) -> Self {
+        self.rules.push((syscall, false));
+        self
+    }
+
+    /// Deny creating any socket other than a Unix domain one. This takes precedence over the
+    /// rules for `socket` itself.
+    pub fn deny_network(mut self) -> Self {
+        self.deny_network = true;
+        self
+    }
+
+    /// Everything, bar opening network sockets.
+    pub fn no_network() -> Self {
+        Self::allow_all().deny_network()
+    }
+
+    /// Everything, bar administering the machine: tracing other processes, mounting,
+    /// rebooting, loading kernel modules and the like.
+    pub fn no_admin() -> Self {
+        [
+            libc::SYS_ptrace,
+            libc::SYS_process_vm_readv,
+            libc::SYS_process_vm_writev,
+            libc::SYS_mount,
+            libc::SYS_umount2,
+            libc::SYS_pivot_root,
+            libc::SYS_chroot,
+            libc::SYS_reboot,
+            libc::SYS_kexec_load,
+            libc::SYS_kexec_file_load,
+            libc::SYS_init_module,
+            libc::SYS_finit_module,
+            libc::SYS_delete_module,
+            libc::SYS_swapon,
+            libc::SYS_swapoff,
+            libc::SYS_settimeofday,
+            libc::SYS_clock_settime,
+            libc::SYS_sethostname,
+            libc::SYS_setdomainname,
+            libc::SYS_bpf,
+            libc::SYS_perf_event_open,
+        ]
+        .into_iter()
+        .fold(Self::allow_all(), Self::deny)
+    }
+}
+
+/// A compiled filter, built in the parent, for the forked script to install.
+pub(crate) struct SeccompPlan {
+    filter: Vec<sock_filter>,
+}
+
+impl SeccompPlan {
+    pub(crate) fn new(seccomp: &Seccomp) -> Self {
+        Self {
+            filter: Self::compile(seccomp),
+        }
+    }
+
+    fn compile(seccomp: &Seccomp) -> Vec<sock_filter> {
+        fn statement(code: u16, k: u32) -> sock_filter {
+            sock_filter {
+                code,
+                jt: 0,
+                jf: 0,
+                k,
+            }
+        }
+        fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+            sock_filter {
+                code,
+                jt,
+                jf,
+                k,
+            }
+        }
+        let action = |allow| match allow {
+            true => SECCOMP_RET_ALLOW,
+            false => SECCOMP_RET_USER_NOTIF,
+        };
+
+        let Some(arch) = AUDIT_ARCH else {
+            // We don't know how to check we're looking at the right system call numbers, so
+            // `install` refuses to.
+            return Vec::new();
+        };
+        let mut filter = vec![
+            statement(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JEQ_K, arch, 1, 0),
+            statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, DATA_NR),
+        ];
+        if cfg!(target_arch = "x86_64") {
+            filter.push(jump(BPF_JGE_K, X32_SYSCALL_BIT, 0, 1));
+            filter.push(statement(BPF_RET_K, action(false)));
+        }
+        for syscall in ALWAYS_ALLOWED {
+            filter.push(jump(BPF_JEQ_K, syscall as u32, 0, 1));
+            filter.push(statement(BPF_RET_K, action(true)));
+        }
+        if seccomp.deny_network {
+            // Both branches return, so anything skipping them still has the number loaded.
+            filter.push(jump(BPF_JEQ_K, libc::SYS_socket as u32, 0, 4));
+            filter.push(statement(BPF_LD_W_ABS, DATA_ARG0));
+            filter.push(jump(BPF_JEQ_K, AF_UNIX as u32, 0, 1));
+            filter.push(statement(BPF_RET_K, action(true)));
+            filter.push(statement(BPF_RET_K, action(false)));
+        }
+        for (syscall, allow) in &seccomp.rules {
+            filter.push(jump(BPF_JEQ_K, *syscall as u32, 0, 1));
+            filter.push(statement(BPF_RET_K, action(*allow)));
+        }
+        filter.push(statement(BPF_RET_K, action(seccomp.default_allow)));
+        filter
+    }
+
+    /// Set `PR_SET_NO_NEW_PRIVS` and install the filter on the calling process, sending the
+    /// listener for its notifications to the supervisor down `channel`, a Unix socket. Only
+    /// ever call this in the script, straight before exec.
+    ///
+    /// On failure, returns the stage which failed, with errno left as it set it.
+    pub(crate) unsafe fn install(&self, channel: c_int) -> Result<(), SpawnStage> {
+        if AUDIT_ARCH.is_none() {
+            *libc::__errno_location() = ENOSYS;
+            return Err(SpawnStage::Seccomp);
+        }
+        let program = sock_fprog {
+            len: self.filter.len() as u16,
+            filter: self.filter.as_ptr() as *mut sock_filter,
+        };
+        if prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+            return Err(SpawnStage::Seccomp);
+        }
+        let listener = syscall(
+            SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            &program,
+        );
+        if listener == -1 {
+            return Err(SpawnStage::Seccomp);
+        }
+        let listener = listener as c_int;
+        let sent = Self::send_fd(channel, listener);
+        // Once the listener is closed here, a denied call fails with ENOSYS rather than
+        // waiting for a supervisor which never got it - so we can still report failure.
+        let errno = *libc::__errno_location();
+        close(listener);
+        close(channel);
+        *libc::__errno_location() = errno;
+        match sent {
+            true => Ok(()),
+            false => Err(SpawnStage::Seccomp),
+        }
+    }
+
+    unsafe fn send_fd(channel: c_int, fd: c_int) -> bool {
+        let mut control = [0u64; CONTROL_WORDS];
+        let mut byte = 0u8;
+        let mut iov = iovec {
+            iov_base: &mut byte as *mut u8 as *mut c_void,
+            iov_len: 1,
+        };
+        let mut message = MaybeUninit::<msghdr>::zeroed().assume_init();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut c_void;
+        message.msg_controllen = size_of_val(&control) as _;
+        let header: *mut cmsghdr = CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = SOL_SOCKET;
+        (*header).cmsg_type = SCM_RIGHTS;
+        (*header).cmsg_len = CMSG_LEN(size_of::<c_int>() as c_uint) as _;
+        std::ptr::write_unaligned(CMSG_DATA(header) as *mut c_int, fd);
+        loop {
+            match sendmsg(channel, &message, 0) {
+                -1 if *libc::__errno_location() == EINTR => continue,
+                sent => return sent == 1,
+            }
+        }
+    }
+
+    /// Receive the script's listener from `channel`, or -1 if it died, or failed, before
+    /// installing the filter. Only ever call this in the supervisor.
+    pub(crate) unsafe fn receive_listener(channel: c_int) -> c_int {
+        let mut control = [0u64; CONTROL_WORDS];
+        let mut byte = 0u8;
+        let mut iov = iovec {
+            iov_base: &mut byte as *mut u8 as *mut c_void,
+            iov_len: 1,
+        };
+        let mut message = MaybeUninit::<msghdr>::zeroed().assume_init();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut c_void;
+        message.msg_controllen = size_of_val(&control) as _;
+        loop {
+            match recvmsg(channel, &mut message, MSG_CMSG_CLOEXEC) {
+                -1 if *libc::__errno_location() == EINTR => continue,
+                1 => break,
+                _ => return -1,
+            }
+        }
+        let header = CMSG_FIRSTHDR(&message);
+        if header.is_null() || (*header).cmsg_type != SCM_RIGHTS {
+            return -1;
+        }
+        std::ptr::read_unaligned(CMSG_DATA(header) as *const c_int)
+    }
+
+    /// Handle a pending notification on `listener`: kill the process which made the denied
+    /// call, as `SECCOMP_RET_KILL_PROCESS` would, and fail the call. `record` is first given
+    /// the process's pid and the call's number, while the process is still there to look at.
+    /// Only ever call this in the supervisor.
+    pub(crate) unsafe fn deny(listener: c_int, record: impl FnOnce(c_int, c_int)) {
+        let mut notif = MaybeUninit::<SeccompNotif>::zeroed().assume_init();
+        if ioctl(listener, SECCOMP_IOCTL_NOTIF_RECV as _, &mut notif) == -1 {
+            // It had already gone.
+            return;
+        }
+        let pid = notif.pid as c_int;
+        record(pid, notif.data.nr);
+        kill(pid, SIGSYS);
+        let response = SeccompNotifResp {
+            id: notif.id,
+            val: 0,
+            error: -EPERM,
+            flags: 0,
+        };
+        ioctl(listener, SECCOMP_IOCTL_NOTIF_SEND as _, &response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Seccomp;
+    use crate::{Command, RashError};
+
+    #[test]
+    fn test_seccomp_allows_what_isnt_denied() -> anyhow::Result<()> {
+        let output =
+            Command::new("echo hi; ls / > /dev/null").seccomp(Seccomp::no_admin()).output()?;
+        Ok(assert_eq!(output.stdout, "hi\n"))
+    }
+
+    #[test]
+    fn test_seccomp_no_network() {
+        let command = Command::new("echo hi > /dev/tcp/127.0.0.1/1").seccomp(Seccomp::no_network());
+        match command.output() {
+            Err(RashError::SyscallBlocked {
+                syscall,
+                name,
+                ..
+            }) => {
+                assert_eq!(syscall, libc::SYS_socket);
+                assert_eq!(name, "bash");
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_seccomp_names_the_process_which_was_killed() {
+        let seccomp = Seccomp::allow_all().deny(libc::SYS_mkdir).deny(libc::SYS_mkdirat);
+        let command =
+            Command::new("mkdir /tmp/i/should/not/exist; echo carried on").seccomp(seccomp);
+        match command.output() {
+            Err(RashError::SyscallBlocked {
+                syscall,
+                name,
+                ..
+            }) => {
+                assert!([libc::SYS_mkdir, libc::SYS_mkdirat].contains(&syscall));
+                assert_eq!(name, "mkdir");
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_seccomp_deny_all() {
+        let command = Command::new("true").seccomp(Seccomp::deny_all());
+        assert!(matches!(command.output(), Err(RashError::SyscallBlocked { .. })));
+    }
+
+    #[test]
+    fn test_seccomp_cant_deny_what_the_script_needs() -> Result<(), RashError> {
+        let seccomp = Seccomp::allow_all().deny(libc::SYS_execve).deny(libc::SYS_close);
+        let output = Command::new("/bin/echo hi").seccomp(seccomp).output()?;
+        Ok(assert_eq!(output.stdout, "hi\n"))
+    }
+
+    #[test]
+    fn test_seccomp_first_rule_wins() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let seccomp = [libc::SYS_mkdir, libc::SYS_mkdirat]
+            .into_iter()
+            .fold(Seccomp::allow_all(), Seccomp::allow)
+            .deny(libc::SYS_mkdir)
+            .deny(libc::SYS_mkdirat);
+        let command = format!("mkdir {}/new", dir.path().display());
+        Command::new(command).seccomp(seccomp).output()?;
+        Ok(assert!(dir.path().join("new").is_dir()))
+    }
+}
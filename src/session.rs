@@ -0,0 +1,584 @@
+use libc::{c_int, c_void, poll, pollfd, read, write, EINTR, POLLIN, SIGKILL};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    command::Command,
+    error::{ErrorContext, RashError},
+    output::{Output, Usage},
+    process::{Process, ProcessError},
+};
+
+/// A long-lived bash process which runs one command after another, so that what each command
+/// does to the shell - setting variables, defining functions, `cd`ing, `source`ing files - is
+/// still there for the next, and bash only has to start up once.
+///
+/// Each command is run with stdin redirected from `/dev/null`. Its stdout and stderr are
+/// picked out of the shell's by markers which the shell writes after the command, each
+/// including a random string chosen for the session.
+///
+/// If the shell dies, e.g. because a command called `exit`, the command which was running
+/// fails with [`RashError::SessionDied`], and the next command starts a fresh shell, without
+/// any of the old one's state. The same goes for a shell which is killed for taking longer
+/// than its [timeout](Session::set_timeout), except that the command fails with
+/// [`RashError::TimedOut`].
+///
+/// ```
+/// use rsbash::{RashError, Session};
+///
+/// pub fn stateful() -> Result<(), RashError> {
+///     let mut session = Session::new()?;
+///     session.run("cd /tmp; greeting=hello")?;
+///     let output = session.run("echo $greeting from $(pwd)")?;
+///     assert_eq!(output.stdout, "hello from /tmp\n");
+///     assert_eq!(session.var("greeting")?.as_deref(), Some("hello"));
+///     Ok(())
+/// }
+/// ```
+pub struct Session {
+    command: Command,
+    marker: String,
+    shell: Option<Shell>,
+    started: bool,
+    restarts: usize,
+    timeout: Option<Duration>,
+}
+
+/// What was read from stdout and stderr.
+type Streams = (Vec<u8>, Vec<u8>);
+
+/// Why a command's markers never turned up, along with whatever was read before then.
+enum Lost {
+    /// The shell died.
+    Died(Streams),
+    /// The shell is still running, but the command ran out of time.
+    TimedOut(Streams),
+}
+
+struct Shell {
+    process: Process,
+    /// Whatever was read from stdout and stderr after the last command's markers, e.g. from a
+    /// background job, which belongs to the next command.
+    pending: [Vec<u8>; 2],
+}
+
+impl Session {
+    /// Start a session in a plain bash shell.
+    pub fn new() -> Result<Self, RashError> {
+        Command::new("").session()
+    }
+
+    /// Start a session whose shell is spawned with `command`'s options, running its script
+    /// first. See [`Command::session`].
+    pub(crate) fn start(command: Command) -> Result<Self, RashError> {
+        let option = command.side_channel_option().or(command.retries().then_some("retry"));
+        if let Some(option) = option {
+            let error = RashError::InvalidOption {
+                message: format!("`{option}` isn't supported on a session"),
+                context: Box::default(),
+            };
+            return Err(error.with_context(ErrorContext {
+                command: Some(command.description()),
+                ..ErrorContext::default()
+            }));
+        }
+        let mut session = Self {
+            command,
+            marker: Self::marker(),
+            shell: None,
+            started: false,
+            restarts: 0,
+            timeout: None,
+        };
+        session.shell()?;
+        Ok(session)
+    }
+
+    /// Run `script` in the shell, returning its exit code, stdout and stderr.
+    ///
+    /// As with [`Command::output`], this returns [`RashError::CommandNotFound`] or
+    /// [`RashError::CommandNotExecutable`] if the script's last command couldn't be found or
+    /// run. Only the `ret_val`, `stdout`, `stderr`, `started` and `finished` fields of the
+    /// [`Output`] are filled in.
+    pub fn run<S: AsRef<str>>(&mut self, script: S) -> Result<Output, RashError> {
+        let script = script.as_ref();
+        let start = Instant::now();
+        let started = SystemTime::now();
+        let command = match self.command.redacted() {
+            true => "<redacted>".to_string(),
+            false => script.to_string(),
+        };
+        let context = |stdout: &[u8], stderr: &[u8], pid| ErrorContext {
+            command: Some(command.clone()),
+            pid,
+            elapsed: Some(start.elapsed()),
+            stdout: String::from_utf8_lossy(stdout).into_owned(),
+            stderr: String::from_utf8_lossy(stderr).into_owned(),
+            trace: Vec::new(),
+        };
+        if let Some(pos) = script.bytes().position(|b| b == 0) {
+            let error = RashError::NullByteInCommand {
+                pos,
+                context: Box::default(),
+            };
+            return Err(error.with_context(context(&[], &[], None)));
+        }
+
+        let marker = self.marker.clone();
+        let timeout = self.timeout;
+        let shell = self.shell()?;
+        let pid = shell.process.pid();
+        // From now, so that starting a new shell doesn't count against the command.
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let received = shell.send(script).and_then(|()| shell.receive(marker.as_bytes(), deadline));
+        let ((stdout, stderr), ret_val) = match received {
+            Ok(received) => received,
+            Err(Lost::TimedOut((stdout, stderr))) => {
+                let mut shell = self.shell.take().expect("The shell was just started.");
+                // There's no telling what state it's in now, so it's replaced like a dead one.
+                if let Err(e) = shell.process.kill(SIGKILL) {
+                    return Err(RashError::from(e).with_context(context(&stdout, &stderr, pid)));
+                }
+                // It can only have been killed, as we meant it to be.
+                let _ = unsafe { shell.process.close() };
+                let error = RashError::TimedOut {
+                    timeout: timeout.unwrap_or_default(),
+                    context: Box::default(),
+                };
+                return Err(error.with_context(context(&stdout, &stderr, pid)));
+            }
+            Err(Lost::Died((stdout, stderr))) => {
+                let mut shell = self.shell.take().expect("The shell was just started.");
+                // Reported as bash reports its own commands being killed.
+                let ret_val = match unsafe { shell.process.close() } {
+                    Ok(ret_val) => ret_val,
+                    Err(ProcessError::OpenDidNotCloseNormally(signal)) => 128 + signal,
+                    Err(e) => {
+                        return Err(RashError::from(e).with_context(context(&stdout, &stderr, pid)))
+                    }
+                };
+                let error = RashError::SessionDied {
+                    ret_val,
+                    context: Box::default(),
+                };
+                return Err(error.with_context(context(&stdout, &stderr, pid)));
+            }
+        };
+
+        let (stdout, stderr) = match (String::from_utf8(stdout), String::from_utf8(stderr)) {
+            (Ok(stdout), Ok(stderr)) => (stdout, stderr),
+            (Err(e), Ok(stderr)) => {
+                let error = RashError::FailedToReadStdout {
+                    message: e.to_string(),
+                    context: Box::default(),
+                };
+                return Err(error.with_context(context(e.as_bytes(), stderr.as_bytes(), pid)));
+            }
+            (stdout, Err(e)) => {
+                let stdout = stdout.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                let error = RashError::FailedToReadStderr {
+                    message: e.to_string(),
+                    context: Box::default(),
+                };
+                return Err(error.with_context(context(&stdout, e.as_bytes(), pid)));
+            }
+        };
+        let error = match ret_val {
+            126 => RashError::CommandNotExecutable {
+                message: stderr.clone(),
+                context: Box::default(),
+            },
+            127 => RashError::CommandNotFound {
+                message: stderr.clone(),
+                context: Box::default(),
+            },
+            _ => {
+                return Ok(Output {
+                    ret_val,
+                    stdout,
+                    stderr,
+                    stragglers: Vec::new(),
+                    pipestatus: None,
+                    trace: None,
+                    usage: Usage::default(),
+                    started,
+                    finished: SystemTime::now(),
+                    landlock_abi: None,
+                })
+            }
+        };
+        Err(error.with_context(context(stdout.as_bytes(), stderr.as_bytes(), pid)))
+    }
+
+    /// The shell's current working directory.
+    pub fn cwd(&mut self) -> Result<PathBuf, RashError> {
+        Ok(PathBuf::from(self.run("builtin printf '%s' \"$PWD\"")?.stdout))
+    }
+
+    /// The value of the shell variable `name`, or `None` if it isn't set (or isn't a valid
+    /// name). For an array, this is its first element.
+    pub fn var(&mut self, name: &str) -> Result<Option<String>, RashError> {
+        let mut chars = name.chars();
+        let valid = chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+            && chars.all(|c| c == '_' || c.is_ascii_alphanumeric());
+        if !valid {
+            return Ok(None);
+        }
+        let output = self.run(format!("[[ -v {name} ]] && builtin printf '%s' \"${name}\""))?;
+        Ok((output.ret_val == 0).then_some(output.stdout))
+    }
+
+    /// Give each command `timeout` to finish in, or as long as it takes if `None`, which is
+    /// the default. A command which is still running when its time is up fails with
+    /// [`RashError::TimedOut`], and takes the shell with it, as it's no longer known to be
+    /// listening: the shell is killed, and a fresh one started for the next command.
+    ///
+    /// This also covers commands which leave the shell running but no longer answering, e.g.
+    /// by sending its stdout elsewhere while holding on to the original, as with
+    /// `exec 3>&1 >/dev/null`. (Letting go of it, as with plain `exec >/dev/null`, is spotted
+    /// straight away, and the shell is treated as dead.)
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// How long each command has to finish in, if there's a limit. See
+    /// [`Session::set_timeout`].
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// How many times the shell has had to be restarted after dying.
+    pub fn restarts(&self) -> usize {
+        self.restarts
+    }
+
+    /// The pid of the shell, or `None` if it died and hasn't been restarted yet.
+    pub fn pid(&self) -> Option<c_int> {
+        self.shell.as_ref().and_then(|shell| shell.process.pid())
+    }
+
+    /// The running shell, starting a new one if need be.
+    fn shell(&mut self) -> Result<&mut Shell, RashError> {
+        if self.shell.is_none() {
+            // Each command is sent null terminated, as it can't contain a null byte itself.
+            let script = format!(
+                "__rash_marker={marker}\n\
+                 while IFS= read -r -d '' __rash_command; do\n\
+                 \x20   eval -- \"$__rash_command\" < /dev/null\n\
+                 \x20   builtin printf '%s %d\\n' \"$__rash_marker\" \"$?\"\n\
+                 \x20   builtin printf '%s\\n' \"$__rash_marker\" >&2\n\
+                 done\n",
+                marker = self.marker,
+            );
+            let process = self.command.open_shell(&script)?;
+            self.shell = Some(Shell {
+                process,
+                pending: [Vec::new(), Vec::new()],
+            });
+            if std::mem::replace(&mut self.started, true) {
+                self.restarts += 1;
+            }
+            let setup = self.command.script().to_string();
+            if !setup.is_empty() {
+                self.run(setup)?;
+            }
+        }
+        Ok(self.shell.as_mut().expect("The shell was just started."))
+    }
+
+    /// A string no command is going to print by accident.
+    fn marker() -> String {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::UNIX_EPOCH.elapsed().unwrap_or_default().as_nanos());
+        let high = hasher.finish();
+        hasher.write_u32(std::process::id());
+        format!("__rash_{:016x}{:016x}__", high, hasher.finish())
+    }
+}
+
+impl Shell {
+    /// Write `script`, null terminated, to the shell's stdin. On failure, the shell is dead.
+    fn send(&mut self, script: &str) -> Result<(), Lost> {
+        let fd = self.process.stdio()[0];
+        let mut bytes = Vec::with_capacity(script.len() + 1);
+        bytes.extend_from_slice(script.as_bytes());
+        bytes.push(0);
+        let mut sent = 0;
+        while sent < bytes.len() {
+            let rest = &bytes[sent..];
+            match unsafe { write(fd, rest.as_ptr() as *const c_void, rest.len()) } {
+                -1 if errno() == EINTR => continue,
+                n if n > 0 => sent += n as usize,
+                _ => return Err(Lost::Died((Vec::new(), Vec::new()))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read stdout and stderr until both markers have turned up, returning what came before
+    /// them, and the command's exit code from the stdout marker. On failure, the shell is dead,
+    /// or `deadline` has passed, and this returns whatever was read.
+    fn receive(
+        &mut self,
+        marker: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<(Streams, c_int), Lost> {
+        let fds = self.process.stdio();
+        let mut streams = std::mem::take(&mut self.pending);
+        let mut ends: [Option<(usize, usize)>; 2] = [None, None];
+        // How far into each stream there's definitely no marker, so big outputs are only
+        // searched once.
+        let mut searched = [0, 0];
+        let mut buffer = [0u8; 8192];
+        loop {
+            for (i, stream) in streams.iter().enumerate() {
+                if ends[i].is_none() {
+                    ends[i] = Self::find_marker(&stream[searched[i]..], marker, i == 0)
+                        .map(|(end, next)| (searched[i] + end, searched[i] + next));
+                    // Leaving room for a marker, and its exit code, which haven't all arrived.
+                    searched[i] = stream.len().saturating_sub(marker.len() + 16).max(searched[i]);
+                }
+            }
+            if ends.iter().all(Option::is_some) {
+                break;
+            }
+            // Both at once, so the shell can't block writing to one while we wait on the other.
+            let mut poll_fds = [1, 2].map(|i| pollfd {
+                fd: match ends[i - 1] {
+                    Some(_) => -1,
+                    None => fds[i],
+                },
+                events: POLLIN,
+                revents: 0,
+            });
+            // Rounded up, so as not to spin through the last millisecond.
+            let timeout =
+                match deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+                    None => -1,
+                    Some(left) if left.is_zero() => {
+                        let [stdout, stderr] = streams;
+                        return Err(Lost::TimedOut((stdout, stderr)));
+                    }
+                    Some(left) => left.as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int,
+                };
+            match unsafe { poll(poll_fds.as_mut_ptr(), 2, timeout) } {
+                -1 if errno() == EINTR => continue,
+                -1 => return Err(Lost::Died((streams[0].clone(), streams[1].clone()))),
+                _ => {}
+            }
+            for (i, poll_fd) in poll_fds.iter().enumerate() {
+                if poll_fd.revents == 0 {
+                    continue;
+                }
+                match unsafe { read(poll_fd.fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()) }
+                {
+                    -1 if errno() == EINTR => {}
+                    n if n > 0 => streams[i].extend_from_slice(&buffer[..n as usize]),
+                    _ => {
+                        let [stdout, stderr] = streams;
+                        return Err(Lost::Died((stdout, stderr)));
+                    }
+                }
+            }
+        }
+
+        let [(stdout_end, stdout_next), (stderr_end, stderr_next)] = ends.map(Option::unwrap);
+        let [mut stdout, mut stderr] = streams;
+        let status = &stdout[stdout_end + marker.len() + 1..stdout_next - 1];
+        let ret_val = std::str::from_utf8(status).ok().and_then(|s| s.parse().ok()).unwrap_or(-1);
+        self.pending = [stdout.split_off(stdout_next), stderr.split_off(stderr_next)];
+        stdout.truncate(stdout_end);
+        stderr.truncate(stderr_end);
+        Ok(((stdout, stderr), ret_val))
+    }
+
+    /// Where the marker starts in `stream`, and where whatever comes after it does. On stdout,
+    /// the marker is followed by a space and the exit code.
+    fn find_marker(stream: &[u8], marker: &[u8], stdout: bool) -> Option<(usize, usize)> {
+        let start = stream.windows(marker.len()).position(|window| window == marker)?;
+        let rest = &stream[start + marker.len()..];
+        let line = rest.iter().position(|b| *b == b'\n')?;
+        match (stdout, line) {
+            (false, 0) => Some((start, start + marker.len() + 1)),
+            (true, 2..) if rest[0] == b' ' => Some((start, start + marker.len() + line + 1)),
+            _ => None,
+        }
+    }
+}
+
+fn errno() -> c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use super::Session;
+    use crate::{Command, RashError, Retry};
+
+    #[test]
+    fn test_session_keeps_state_between_commands() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        session.run("x=1; greet() { echo \"hello $1\"; }; cd /tmp")?;
+        assert_eq!(session.run("greet $x; pwd")?.stdout, "hello 1\n/tmp\n");
+        assert_eq!(session.cwd()?, PathBuf::from("/tmp"));
+        assert_eq!(session.var("x")?.as_deref(), Some("1"));
+        assert_eq!(session.var("y")?, None);
+        Ok(assert_eq!(session.var("not a name")?, None))
+    }
+
+    #[test]
+    fn test_session_separates_each_commands_output() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        let output = session.run("echo out; printf err >&2; false")?;
+        assert_eq!(
+            (output.ret_val, output.stdout, output.stderr),
+            (1, "out\n".into(), "err".into())
+        );
+        let output = session.run("printf more")?;
+        Ok(assert_eq!(
+            (output.ret_val, output.stdout, output.stderr),
+            (0, "more".into(), "".into())
+        ))
+    }
+
+    #[test]
+    fn test_session_reads_both_streams_at_once() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        let output = session.run("head -c 300000 /dev/zero >&2; head -c 300000 /dev/zero")?;
+        assert_eq!(output.stdout.len(), 300000);
+        Ok(assert_eq!(output.stderr.len(), 300000))
+    }
+
+    #[test]
+    fn test_session_commands_dont_read_its_stdin() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        assert_eq!(session.run("cat; read line; echo $?")?.stdout, "1\n");
+        Ok(assert_eq!(session.run("echo still here")?.stdout, "still here\n"))
+    }
+
+    #[test]
+    fn test_session_survives_failing_commands() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        session.run("x=1")?;
+        assert!(matches!(session.run("i_do_not_exist"), Err(RashError::CommandNotFound { .. })));
+        assert_eq!(session.run("if then")?.ret_val, 2);
+        Ok(assert_eq!(session.var("x")?.as_deref(), Some("1")))
+    }
+
+    #[test]
+    fn test_session_restarts_its_shell() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        session.run("x=1")?;
+        let error = session.run("echo bye; exit 3").unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SessionDied {
+                ret_val: 3,
+                ..
+            }
+        ));
+        assert_eq!(error.stdout(), "bye\n");
+        assert_eq!(session.pid(), None);
+
+        assert_eq!(session.run("echo hi")?.stdout, "hi\n");
+        assert_eq!(session.var("x")?, None);
+        assert_eq!(session.restarts(), 1);
+
+        let error = session.run("kill -9 $$").unwrap_err();
+        assert!(matches!(
+            error,
+            RashError::SessionDied {
+                ret_val: 137,
+                ..
+            }
+        ));
+        assert_eq!(session.run("echo hi again")?.stdout, "hi again\n");
+        Ok(assert_eq!(session.restarts(), 2))
+    }
+
+    #[test]
+    fn test_session_times_out_commands() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        assert_eq!(session.timeout(), None);
+        session.set_timeout(Some(Duration::from_millis(200)));
+        session.run("x=1")?;
+        let start = Instant::now();
+        let error = session.run("echo before; sleep 10").unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            error,
+            RashError::TimedOut {
+                timeout: Duration::from_millis(200),
+                context: Box::default(),
+            }
+        );
+        assert_eq!(error.stdout(), "before\n");
+        assert_eq!(session.pid(), None);
+
+        assert_eq!(session.run("echo hi")?.stdout, "hi\n");
+        assert_eq!(session.var("x")?, None);
+        Ok(assert_eq!(session.restarts(), 1))
+    }
+
+    #[test]
+    fn test_session_times_out_a_shell_which_stops_answering() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        session.set_timeout(Some(Duration::from_millis(200)));
+        let error = session.run("exec 3>&1 >/dev/null").unwrap_err();
+        assert!(matches!(error, RashError::TimedOut { .. }));
+        Ok(assert_eq!(session.run("echo hi")?.stdout, "hi\n"))
+    }
+
+    #[test]
+    fn test_session_from_command() -> Result<(), RashError> {
+        let mut session = Command::new("x=1").current_dir("/tmp").session()?;
+        assert_eq!(session.var("x")?.as_deref(), Some("1"));
+        assert_eq!(session.cwd()?, PathBuf::from("/tmp"));
+        // The setup is run again in the new shell.
+        assert!(session.run("exit").is_err());
+        Ok(assert_eq!(session.var("x")?.as_deref(), Some("1")))
+    }
+
+    #[test]
+    fn test_session_rejects_options_it_cant_honour() {
+        let commands = [
+            Command::new("x=1").strict(true),
+            Command::new("x=1").pipestatus(true),
+            Command::new("x=1").trace(true),
+            Command::new("x=1").retry(Retry::new(3)),
+        ];
+        for (command, option) in commands.iter().zip(["strict", "pipestatus", "trace", "retry"]) {
+            let error = command.session().err().unwrap();
+            match &error {
+                RashError::InvalidOption {
+                    message,
+                    ..
+                } => assert!(message.contains(option)),
+                _ => panic!("Expected InvalidOption, got {error:?}"),
+            }
+            assert_eq!(error.command(), Some("x=1"));
+        }
+    }
+
+    #[test]
+    fn test_session_rejects_null_bytes() -> Result<(), RashError> {
+        let mut session = Session::new()?;
+        let error = session.run("echo \0").unwrap_err();
+        Ok(assert!(matches!(
+            error,
+            RashError::NullByteInCommand {
+                pos: 5,
+                ..
+            }
+        )))
+    }
+}
@@ -1,20 +1,17 @@
 use std::str;
 
-use crate::{command::BashCommand, error::RashError, process::Process};
+use crate::{command::Command, error::RashError};
 
 type Out = (i32, String, String);
 
 #[cfg(unix)]
 pub fn __command<S: AsRef<str>>(c: S) -> Result<Out, RashError> {
-    let command = BashCommand::new(c)?;
-    let mut process = Process::new();
-    Ok(unsafe {
-        process.open(command)?;
-        let ret = process.close()?;
-        let stdout = process.stdout()?;
-        let stderr = process.stderr()?;
-        (ret, stdout, stderr)
-    })
+    Ok(Command::new(c).output()?.into())
+}
+
+#[cfg(unix)]
+pub fn __strict_command<S: AsRef<str>>(c: S) -> Result<Out, RashError> {
+    Ok(Command::new(c).strict(true).output()?.into())
 }
 
 #[cfg(test)]
@@ -46,12 +43,34 @@ mod tests {
 
     #[test]
     fn test_commands_return_non_zero() {
-        [("i_am_not_a_valid_executable", 127), ("echo hi | grep 'bye'", 1), ("exit 54;", 54)]
-            .iter()
-            .for_each(move |(c, ret)| {
-                let (r, _, _) = __command(c).unwrap();
-                assert_eq!(r, *ret);
-            });
+        [("echo hi | grep 'bye'", 1), ("exit 54;", 54)].iter().for_each(move |(c, ret)| {
+            let (r, _, _) = __command(c).unwrap();
+            assert_eq!(r, *ret);
+        });
+    }
+
+    #[test]
+    fn test_command_not_found() {
+        assert!(matches!(
+            __command("i_am_not_a_valid_executable"),
+            Err(RashError::CommandNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_command_not_executable() {
+        assert!(matches!(__command("/dev/null"), Err(RashError::CommandNotExecutable { .. })));
+    }
+
+    #[test]
+    fn test_killed_by_signal() {
+        assert!(matches!(
+            __command("kill -9 $$"),
+            Err(RashError::KilledBySignal {
+                signal: 9,
+                ..
+            })
+        ));
     }
 
     #[test]